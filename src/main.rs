@@ -1,39 +1,1081 @@
 use anyhow::{Context, Result};
-use chrono::{DateTime, TimeZone, Utc};
-use clap::Parser;
-use log::{debug, error};
-use rusqlite::Connection;
-use serde::Serialize;
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDate, TimeZone, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use glob::{MatchOptions, Pattern};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, error, warn};
+use notify::{RecursiveMode, Watcher};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use rusqlite::{
+    params, params_from_iter,
+    types::{Value, ValueRef},
+    Connection, OpenFlags, Statement,
+};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
     ffi::OsStr,
     fs,
+    io::{self, IsTerminal, Write},
     path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc,
+    time::Instant,
 };
 use thiserror::Error;
 
+mod serve;
+#[cfg(test)]
+mod tests;
+mod tui;
+
 #[derive(Parser, Debug)]
 #[clap(author, version = "0.1", about, long_about = None)]
 struct Args {
+    #[clap(subcommand)]
+    action: Option<Action>,
+
     /// Update sync date at the end
     #[clap(long)]
     update: bool,
 
-    /// Output annotation in JSON format
+    /// Ask for confirmation before updating the sync-file with --update
+    #[clap(long, requires = "update")]
+    confirm: bool,
+
+    /// Never write the sync-file during this run (including sync point marked in interactive UI)
+    #[clap(long, conflicts_with_all = &["update", "init"])]
+    no_update: bool,
+
+    /// Only print the export to stdout. Sync-file and output files are never written, even if
+    /// --update or output options are given
+    #[clap(long, conflicts_with_all = &["init", "watch"])]
+    read_only: bool,
+
+    /// Output format
+    #[clap(long, value_enum, default_value = "logseq")]
+    format: OutputFormat,
+
+    /// Print available output formats with examples and exit
+    #[clap(long)]
+    format_help: bool,
+
+    /// Print names of available output formats (one per line, or JSON array with --json) and exit
+    #[clap(long)]
+    list_formats: bool,
+
+    /// Write output to a file instead of stdout. File is left untouched if content hasn't changed
+    #[clap(long, short, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Encoding of written files and stdout
+    #[clap(long, value_enum, value_name = "ENCODING", default_value = "utf8")]
+    output_encoding: OutputEncoding,
+
+    /// Write annotations with notes and plain highlights to two separate files
+    #[clap(
+        long,
+        number_of_values = 2,
+        value_names = &["NOTES_PATH", "HIGHLIGHTS_PATH"],
+        conflicts_with = "output"
+    )]
+    split: Vec<PathBuf>,
+
+    /// Copy output to the clipboard instead of printing it (requires `pbcopy`)
+    #[clap(long, conflicts_with_all = &["output", "split", "sqlite-out"])]
+    clipboard: bool,
+
+    /// Show progress on stderr while writing --sqlite-out database (only if stderr is a terminal)
+    #[clap(long)]
+    progress: bool,
+
+    /// Write highlights.json, highlights.md (Logseq), highlights.csv and highlights.html to
+    /// given directory
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = &["output", "split", "sqlite-out", "clipboard"]
+    )]
+    export_all: Option<PathBuf>,
+
+    /// Write every book to a separate file in given directory. Files are named after book titles
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = &["output", "split", "sqlite-out", "clipboard", "export-all"]
+    )]
+    output_dir: Option<PathBuf>,
+
+    /// Treat --output as a directory and write highlights of every color to a separate file
+    /// (eg. yellow.md), highlights without color are written to `uncolored` file
+    #[clap(long, requires = "output")]
+    split_by_color: bool,
+
+    /// Write tar archive with every book in a separate file (named after slugified book title)
+    /// to stdout or --output
+    #[clap(
+        long,
+        conflicts_with_all = &["split", "sqlite-out", "clipboard", "export-all", "output-dir", "split-by-color"]
+    )]
+    tar: bool,
+
+    /// Pipe annotations as JSON array to stdin of a shell command and use its stdout as the
+    /// output, eg. `--formatter-cmd 'jq -r .[].selected_text'`
+    #[clap(
+        long,
+        value_name = "CMD",
+        conflicts_with_all = &["sqlite-out", "export-all", "tar"]
+    )]
+    formatter_cmd: Option<String>,
+
+    /// Also write index.md linking to every book file of --output-dir
+    #[clap(long, requires = "output-dir")]
+    book_index: bool,
+
+    /// Write annotations to SQLite database with books and annotations tables. Tables are
+    /// recreated on every export
+    #[clap(long, value_name = "PATH", conflicts_with_all = &["output", "split"])]
+    sqlite_out: Option<PathBuf>,
+
+    /// Merge exported annotations into given JSON export (eg. the one written by previous run).
+    /// Annotations with the same UUID are exported once. Missing file is treated as empty
+    #[clap(long, value_name = "PATH")]
+    combine_with: Option<PathBuf>,
+
+    /// Output annotation in JSON format (same as --format json)
     #[clap(long, short)]
     json: bool,
 
-    /// Output annotation in table format
+    /// Output annotation in table format (same as --format table)
     #[clap(long, short)]
     table: bool,
 
-    /// Output annotation in Obsidian markdown format
+    /// Output annotation in Obsidian markdown format (same as --format obsidian)
     #[clap(long)]
     obsidian: bool,
 
+    /// Output annotations in YAML format (same as --format yaml)
+    #[clap(long)]
+    yaml: bool,
+
+    /// Output Mermaid timeline of highlights by month (same as --format mermaid)
+    #[clap(long)]
+    mermaid: bool,
+
+    /// Output GraphViz graph of books and highlights (same as --format dot)
+    #[clap(long)]
+    dot: bool,
+
+    /// Print one line per annotation with the beginning of the highlight (same as --format
+    /// preview)
+    #[clap(long)]
+    preview: bool,
+
+    /// Output one JSON object per book on a single line (same as --format books-jsonl)
+    #[clap(long)]
+    books_jsonl: bool,
+
+    /// Output W3C Web Annotation collections, one per book (same as --format web-annotation)
+    #[clap(long)]
+    web_annotation: bool,
+
+    /// Output iCalendar reading log with an all-day event for every highlight (same as
+    /// --format ics)
+    #[clap(long)]
+    ics: bool,
+
+    /// Output CSV for Notion database import (same as --format notion)
+    #[clap(long)]
+    notion: bool,
+
+    /// Output only notes, combined into a single markdown document per book (same as
+    /// --format notes). Use with --output-dir to write every book to a separate file
+    #[clap(long)]
+    aggregate_notes: bool,
+
+    /// Output markdown document with annotations grouped by #hashtags of their notes (same as
+    /// --format tags)
+    #[clap(long)]
+    group_by_tag: bool,
+
+    /// Render highlights with the same text (eg. from several editions of a book) once, followed
+    /// by links to all their books, in Logseq and Obsidian formats
+    #[clap(long)]
+    collapse_identical: bool,
+
+    /// Cite the highlighted text after every note of --aggregate-notes
+    #[clap(long)]
+    notes_with_quotes: bool,
+
+    /// Prepend a reference number (eg. "[3]") to every highlight in text and markdown formats
+    #[clap(long)]
+    numbered: bool,
+
+    /// Number highlights across all books or starting from 1 in every book
+    #[clap(long, value_enum, default_value = "global", requires = "numbered")]
+    number_scope: NumberScope,
+
+    /// Write every book as a Hugo post to --output-dir (same as --format hugo)
+    #[clap(long, requires = "output-dir", conflicts_with = "jekyll")]
+    hugo: bool,
+
+    /// Write every book as a Jekyll post to --output-dir (same as --format jekyll)
+    #[clap(long, requires = "output-dir")]
+    jekyll: bool,
+
+    /// Tag of Hugo and Jekyll posts (can be repeated)
+    #[clap(long, value_name = "TAG", default_value = "highlights")]
+    post_tag: Vec<String>,
+
+    /// Browse annotations in interactive terminal UI. Sync point marked in UI is saved on exit
+    #[clap(long, short)]
+    interactive: bool,
+
+    /// Keep running and export new highlights (updating sync-file) every time annotation
+    /// database changes
+    #[clap(long, conflicts_with_all = &["no-update", "confirm", "interactive", "init"])]
+    watch: bool,
+
+    /// Seconds without database changes to wait before exporting in --watch mode
+    #[clap(long, default_value = "5", value_name = "SECONDS")]
+    watch_interval: u64,
+
+    /// Exit without reading annotations if annotation databases were not modified after the
+    /// last sync time (useful for frequent runs from cron)
+    #[clap(long)]
+    skip_if_unchanged: bool,
+
     /// Read all annotations, not from last sync time
     #[clap(short)]
     all: bool,
+
+    /// Include annotations deleted in iBooks
+    #[clap(long)]
+    include_deleted: bool,
+
+    /// Export from iBooks container of a named profile instead of the default one. Profiles are
+    /// listed in `profiles` file next to the sync-file as `name = path to container` lines.
+    /// Every profile has its own sync-file
+    #[clap(long, value_name = "NAME", value_parser = parse_profile)]
+    profile: Option<String>,
+
+    /// Location of iBooks container (`com.apple.iBooksX` directory or a home dir containing
+    /// `Library/Containers/com.apple.iBooksX`), eg. on an external volume
+    #[clap(long, value_name = "PATH", conflicts_with = "profile")]
+    library_root: Option<PathBuf>,
+
+    /// Bundle id of iBooks app, name of its container in `~/Library/Containers`
+    #[clap(long, value_name = "ID", default_value = DEFAULT_BUNDLE_ID)]
+    bundle_id: String,
+
+    /// Read databases from unencrypted iOS/iPadOS backup directory (the one containing
+    /// `Manifest.db`) instead of iBooks container
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = &["profile", "library-root", "watch"]
+    )]
+    ios_backup: Option<PathBuf>,
+
+    /// Annotation database to read instead of the iBooks one, can be a glob (eg. snapshots/*.sqlite)
+    /// and can be repeated. Annotations present in several databases are exported once
+    #[clap(long, value_name = "GLOB", conflicts_with = "ios-backup")]
+    annotation_db: Vec<String>,
+
+    /// Format of the timestamp written to the sync-file
+    #[clap(long, value_enum, default_value = "rfc3339")]
+    sync_format: SyncFormat,
+
+    /// Copy sync-file to `<sync-file>.bak` before updating it
+    #[clap(long)]
+    backup_sync: bool,
+
+    /// Restore sync-file from the backup made by --backup-sync and exit
+    #[clap(
+        long,
+        conflicts_with_all = &["update", "backup-sync", "init", "watch", "interactive", "read-only"]
+    )]
+    restore_sync: bool,
+
+    /// Export only books which title contains given text (case-insensitive, can be repeated)
+    #[clap(long, value_name = "TITLE")]
+    book: Vec<String>,
+
+    /// Export only books listed in a file, one title or asset id per line. Blank lines and
+    /// lines starting with `#` are ignored. Combined with --book using OR
+    #[clap(long, value_name = "PATH")]
+    book_file: Option<PathBuf>,
+
+    /// Export only books which title matches glob pattern, eg. "The Lord of the Rings*"
+    /// (case-insensitive, can be repeated)
+    #[clap(long, value_name = "PATTERN", value_parser = parse_glob)]
+    book_glob: Vec<Pattern>,
+
+    /// Export only book with given asset id (can be repeated). Ids are listed in JSON output
+    #[clap(long, value_name = "ID")]
+    book_id: Vec<String>,
+
+    /// Export only the book which title matches the query best (fuzzy matching, eg. "lotr
+    /// fellowship"). Matched title is printed to stderr
+    #[clap(long, value_name = "QUERY", conflicts_with = "book-id")]
+    fuzzy_book: Option<String>,
+
+    /// Export annotations modified after given date (YYYY-MM-DD or RFC3339), no matter when
+    /// they were created. Sync-file is neither used nor updated
+    #[clap(
+        long,
+        value_name = "DATE",
+        value_parser = parse_date,
+        conflicts_with_all = &["update", "interactive"]
+    )]
+    modified_since: Option<DateTime<Utc>>,
+
+    /// Export annotations created or modified after given date (YYYY-MM-DD or RFC3339), both
+    /// new and edited ones. Sync-file is neither used nor updated
+    #[clap(
+        long,
+        value_name = "DATE",
+        value_parser = parse_date,
+        conflicts_with_all = &["update", "interactive", "modified-since"]
+    )]
+    active_since: Option<DateTime<Utc>>,
+
+    /// Export annotations created after the time stored in given file (RFC3339 or Unix
+    /// timestamp, eg. written by another sync tool) instead of the sync-file
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = &["update", "interactive", "modified-since"]
+    )]
+    since_sync_of: Option<PathBuf>,
+
+    /// Additional sync-files (eg. of other machines, shared via Dropbox). Export starts from the
+    /// earliest sync time of all the sync-files, and all of them are updated with --update
+    #[clap(
+        long,
+        value_name = "PATH",
+        multiple_values = true,
+        conflicts_with = "since-sync-of"
+    )]
+    merge_sync: Vec<PathBuf>,
+
+    /// Annotations created before this date are considered corrupted and skipped
+    #[clap(long, value_name = "DATE", value_parser = parse_date, default_value = "2010-01-01")]
+    min_date: DateTime<Utc>,
+
+    /// Annotations created after this date are considered corrupted and skipped (one day
+    /// after current time by default)
+    #[clap(long, value_name = "DATE", value_parser = parse_date)]
+    max_date: Option<DateTime<Utc>>,
+
+    /// Export only the book which was opened in iBooks most recently
+    #[clap(long, alias = "since-last-opened-book", conflicts_with = "book-id")]
+    last_opened_book: bool,
+
+    /// Export only given number of books which were added to the library or opened most
+    /// recently
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = &["book-id", "last-opened-book"]
+    )]
+    last_books: Option<u32>,
+
+    /// Export at most given number of annotations of every book (first ones by creation time).
+    /// With --update the rest is exported by the next runs
+    #[clap(long, value_name = "N")]
+    max_per_book: Option<u32>,
+
+    /// Skip books with less than given number of annotations (after other filters are applied)
+    #[clap(long, value_name = "N")]
+    min_book_highlights: Option<u32>,
+
+    /// Export only given number of randomly chosen annotations (eg. for a daily review)
+    #[clap(long, value_name = "N", conflicts_with = "update")]
+    sample: Option<usize>,
+
+    /// Seed of --sample, same seed chooses the same annotations
+    #[clap(long, requires = "sample")]
+    seed: Option<u64>,
+
+    /// Export at most given number of annotations (first ones in --sort order, after filters
+    /// are applied)
+    #[clap(long, value_name = "N")]
+    limit: Option<u32>,
+
+    /// Skip given number of annotations, can be combined with --limit to export in pages.
+    /// Annotations are ordered by --sort, and paging is done after filters are applied
+    #[clap(long, value_name = "N")]
+    offset: Option<u32>,
+
+    /// Among annotations of a book with the same highlighted text keep only the one with the
+    /// longest note (the latest one if notes are of the same length)
+    #[clap(long)]
+    dedupe_by_note: bool,
+
+    /// Merge highlights which follow each other in the book and were created within
+    /// --merge-window seconds of each other into a single quote
+    #[clap(long)]
+    merge_consecutive: bool,
+
+    /// Maximum time between consecutive highlights merged by --merge-consecutive
+    #[clap(long, value_name = "SECONDS", default_value = "60")]
+    merge_window: u32,
+
+    /// Instead of annotations print number of annotations in each group, most common first
+    #[clap(long, value_enum, value_name = "GROUP")]
+    count_by: Option<CountBy>,
+
+    /// Instead of annotations print summary statistics: number of annotations, books, notes and
+    /// highlighted words
+    #[clap(long, conflicts_with = "count-by")]
+    stats: bool,
+
+    /// Instead of annotations print how highlights of every book are spread over the book,
+    /// split into given number of bins from beginning to end
+    #[clap(
+        long,
+        value_name = "BINS",
+        value_parser = clap::value_parser!(u32).range(1..=100),
+        conflicts_with_all = &["count-by", "stats"]
+    )]
+    density: Option<u32>,
+
+    /// Only print number of annotations matching the sync time, book and deletion filters
+    /// (without reading annotations), eg. to decide on --limit. Other filters are not applied
+    #[clap(long, conflicts_with_all = &["sql-file", "update", "interactive", "watch"])]
+    estimate: bool,
+
+    /// Instead of annotations print titles (and authors) of annotated books, one per line
+    #[clap(long, conflicts_with_all = &["count-by", "stats", "density"])]
+    list_books: bool,
+
+    /// Print bare titles with --list-books, eg. for `ibooks-export --list-books --raw | fzf`
+    #[clap(long, alias = "plain", requires = "list-books")]
+    raw: bool,
+
+    /// Export only highlights of given color (can be repeated, highlights matching any of the
+    /// colors are exported). Combined with --with-notes using AND
+    #[clap(long, value_enum)]
+    color: Vec<HighlightColor>,
+
+    /// Start Logseq and Obsidian output with a legend of highlight colors and tag every
+    /// highlight with its color
+    #[clap(long)]
+    color_legend: bool,
+
+    /// Meaning of a color shown in --color-legend, eg. yellow="key idea" (can be repeated)
+    #[clap(long, value_name = "COLOR=MEANING", value_parser = parse_color_label)]
+    color_label: Vec<(HighlightColor, String)>,
+
+    /// Export only books with at least one note (all annotations of such books are exported)
+    #[clap(long)]
+    only_books_with_notes: bool,
+
+    /// Export only books marked as finished in iBooks. Books without reading status are skipped
+    #[clap(long, conflicts_with = "unfinished")]
+    finished: bool,
+
+    /// Export only books added to the library after given date (YYYY-MM-DD or RFC3339),
+    /// regardless of when they were highlighted
+    #[clap(long, value_name = "DATE", value_parser = parse_date)]
+    book_added_since: Option<DateTime<Utc>>,
+
+    /// Export only books with a cover image found on disk
+    #[clap(long)]
+    with_cover: bool,
+
+    /// Export only highlights flagged as favorite in iBooks. Nothing is exported if iBooks
+    /// version doesn't support flagging
+    #[clap(long)]
+    favorites_only: bool,
+
+    /// Export only books not marked as finished in iBooks. Books without reading status are
+    /// skipped
+    #[clap(long)]
+    unfinished: bool,
+
+    /// Export only highlights with a note
+    #[clap(long)]
+    with_notes: bool,
+
+    /// Export only books which author contains given text (case-insensitive, can be repeated)
+    #[clap(long, value_name = "NAME")]
+    author: Vec<String>,
+
+    /// Skip books which title contains given text (case-insensitive, can be repeated).
+    /// Takes precedence over --book
+    #[clap(long, value_name = "TITLE")]
+    ignore_book: Vec<String>,
+
+    /// Order of books in formats grouping annotations by book
+    #[clap(long, value_enum, default_value = "alpha")]
+    book_order: BookOrder,
+
+    /// Order of annotations (within a book in formats grouping annotations by book)
+    #[clap(long, value_enum, default_value = "time")]
+    sort: AnnotationOrder,
+
+    /// Show number of characters of highlight text and note in table format
+    #[clap(long)]
+    show_lengths: bool,
+
+    /// Split highlights of every book by color (with a heading per color) in Logseq and
+    /// Obsidian formats
+    #[clap(long)]
+    group_by_color_within_book: bool,
+
+    /// Position of a note relative to the quote in Logseq format
+    #[clap(long, value_enum, default_value = "above")]
+    note_position: NotePosition,
+
+    /// Show `open 'ibooks://...'` command opening the highlight in iBooks in table, Logseq and
+    /// Obsidian formats
+    #[clap(long)]
+    with_open_command: bool,
+
+    /// Don't render quotes of highlights without text in Logseq format, only the note is
+    /// rendered. Highlights without text and note are skipped
+    #[clap(long)]
+    compact_logseq: bool,
+
+    /// Add `color::` property with highlight color to quote blocks in Logseq format
+    #[clap(long)]
+    logseq_color_property: bool,
+
+    /// Start every book in Logseq and Obsidian formats with a BibTeX entry (title, author and
+    /// year and publisher if they are in the library)
+    #[clap(long)]
+    with_bibtex: bool,
+
+    /// Text used in place of an empty highlight in markdown formats
+    #[clap(long, default_value = "-", value_name = "TEXT")]
+    empty_text: String,
+
+    /// Title used for books without a title in the library
+    #[clap(long, default_value = "Unknown Book", value_name = "TITLE")]
+    unknown_book: String,
+
+    /// Tint table rows with the highlight color (disabled if NO_COLOR is set)
+    #[clap(long)]
+    color_rows: bool,
+
+    /// Collapse newlines and tabs in highlights to single spaces in table format,
+    /// so every highlight takes as few lines as possible
+    #[clap(long)]
+    collapse_whitespace: bool,
+
+    /// Strip leading and trailing whitespace and a trailing ellipsis ("…") left by the selection
+    /// from highlighted text
+    #[clap(long)]
+    trim_ellipsis: bool,
+
+    /// Transliterate highlights and notes to ASCII (eg. "é" to "e"), characters without ASCII
+    /// equivalent (like emoji) are removed. This is lossy
+    #[clap(long)]
+    ascii_only: bool,
+
+    /// Expand highlights cut mid-sentence to whole sentences using the text around the
+    /// highlight stored by iBooks (best-effort, highlights without it are left as is)
+    #[clap(long)]
+    snap_to_sentence: bool,
+
+    /// Show dates relative to current time (eg. "3 days ago") in table format
+    #[clap(long)]
+    relative_dates: bool,
+
+    /// Locale (eg. de_DE) used for dates in table and HTML formats and for alphabetical order
+    /// of books
+    #[clap(long, value_name = "CODE", value_parser = parse_locale)]
+    locale: Option<String>,
+
+    /// Render human readable dates in UTC instead of the system timezone
+    #[clap(long, conflicts_with = "tz")]
+    utc: bool,
+
+    /// UTC offset (eg. +03:00) of human readable dates instead of the system timezone. JSON
+    /// timestamps are written with this offset too
+    #[clap(long, value_name = "OFFSET", value_parser = parse_offset, allow_hyphen_values = true)]
+    tz: Option<FixedOffset>,
+
+    /// Show text around the highlight (as stored by iBooks) beneath the quote in Logseq and
+    /// Obsidian formats. Context is always included in JSON output
+    #[clap(long)]
+    with_context: bool,
+
+    /// Line written before each annotation in Logseq and Obsidian formats,
+    /// eg. "::: quote" for a Pandoc fenced div
+    #[clap(long, default_value = "", hide_default_value = true)]
+    block_prefix: String,
+
+    /// Line written after each annotation in Logseq and Obsidian formats
+    #[clap(long, default_value = "", hide_default_value = true)]
+    block_suffix: String,
+
+    /// Written before notes in Logseq, Obsidian, HTML and blog post formats, so notes can be
+    /// told apart from quotes, eg. "Note: "
+    #[clap(
+        long,
+        value_name = "STR",
+        default_value = "",
+        hide_default_value = true
+    )]
+    note_prefix: String,
+
+    /// Escape Markdown special characters (\ ` * _ # [ ]) with backslash in highlights and notes
+    /// of Logseq, Obsidian and blog post formats, so the text is rendered verbatim
+    #[clap(long)]
+    escape_markdown: bool,
+
+    /// Written between annotations in Logseq and Obsidian formats instead of the default (empty
+    /// line in Obsidian, nothing in Logseq). Escape sequences `\n`, `\t` and `\\` are supported
+    #[clap(long, value_name = "SEPARATOR", value_parser = parse_escapes)]
+    annotation_separator: Option<String>,
+
+    /// Written between books in Logseq and Obsidian formats instead of the default (`---` line
+    /// in Obsidian, nothing in Logseq). Escape sequences `\n`, `\t` and `\\` are supported
+    #[clap(long, value_name = "SEPARATOR", value_parser = parse_escapes)]
+    book_separator: Option<String>,
+
+    /// Book heading in Logseq, Obsidian and HTML formats. Placeholders: {title} (rendered as a
+    /// link in Logseq and Obsidian), {author}, {asset_id} and {count} (number of highlights)
+    #[clap(long, value_name = "TEMPLATE", default_value = "{title}")]
+    heading_format: String,
+
+    /// Put all books of Logseq export under a single parent block, eg. `[[Highlights {date}]]`.
+    /// {date} is replaced with the current date (`YYYY-MM-DD`)
+    #[clap(long, value_name = "TEMPLATE")]
+    logseq_parent: Option<String>,
+
+    /// Separator between highlights joined in book-summary format (empty line by default)
+    #[clap(long, default_value = "\n\n", hide_default_value = true)]
+    summary_separator: String,
+
+    /// Detect iBooks databases, create an empty sync-file and print their locations.
+    /// Safe to run multiple times
+    #[clap(long)]
+    init: bool,
+
+    /// Check that databases and sync-file are readable and supported, then exit
+    #[clap(long)]
+    validate: bool,
+
+    /// Print metadata of all the books in the library as JSON array (annotations aren't read),
+    /// then exit
+    #[clap(long, conflicts_with_all = &["update", "init", "watch", "interactive"])]
+    library_dump: bool,
+
+    /// Report the last sync time, the latest annotation time and the number of annotations
+    /// created after the sync, then exit. Nothing is exported or updated
+    #[clap(long, conflicts_with_all = &["update", "init", "watch", "interactive"])]
+    verify_sync: bool,
+
+    /// Print annotations which book is not found in the library (skipped by export) with their
+    /// ids, asset ids and text, then exit. Nothing is exported or updated
+    #[clap(long, conflicts_with_all = &["update", "init", "watch", "interactive", "verify-sync"])]
+    orphans: bool,
+
+    /// Remember the current schema of the annotation database, so it's not reported as changed
+    /// anymore (see README)
+    #[clap(long)]
+    accept_schema: bool,
+
+    /// Print length of every highlight in the database and in the output (in characters) to
+    /// stderr, to find out if highlights are truncated
+    #[clap(long)]
+    debug_lengths: bool,
+
+    /// Print SQL query reading annotations (with parameters bound) to stderr
+    #[clap(long)]
+    explain: bool,
+
+    /// Fail (listing offending annotations) instead of silently skipping annotations without
+    /// text, of books missing in the library or with invalid dates
+    #[clap(long)]
+    strict: bool,
+
+    /// Skip rows which can't be read (eg. with unexpected column types) instead of failing,
+    /// annotations of other rows are exported
+    #[clap(long, conflicts_with = "strict")]
+    skip_errors: bool,
+
+    /// Abort if reading annotations takes longer than given number of seconds
+    #[clap(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: Option<u64>,
+
+    /// Print time spent in each phase of the export (opening databases, query, formatting,
+    /// writing) to stderr
+    #[clap(long)]
+    profile_timings: bool,
+
+    /// Read annotations with a custom SELECT returning selected text, note, Core Data timestamp
+    /// and book title (in this order). Library database is attached as `l`, the only `?`
+    /// parameter (if any) is bound to the last sync time. Other database filters are ignored
+    #[clap(long, value_name = "PATH", conflicts_with_all = &["annotation-db", "ios-backup"])]
+    sql_file: Option<PathBuf>,
+
+    /// Comma separated list of annotation fields included in JSON output (all fields by default)
+    #[clap(long, value_delimiter = ',', value_name = "FIELDS")]
+    fields: Vec<String>,
+
+    /// Output annotation time as Unix timestamp (seconds) instead of RFC3339 in JSON formats
+    #[clap(long)]
+    epoch_timestamps: bool,
+
+    /// Always write `note` field in JSON and YAML formats (`null` for highlights without a note)
+    #[clap(long, alias = "json-null-notes")]
+    include_null_notes: bool,
+
+    /// Wrap JSON output in an object with generation time and number of annotations:
+    /// {"generated_at": ..., "source": "ibooks", "count": ..., "annotations": [...]}
+    #[clap(long)]
+    envelope: bool,
+
+    /// Output JSON object with annotations grouped by local month: {"2023-01": [...], ...}
+    #[clap(long, conflicts_with = "envelope")]
+    group_by_month: bool,
+
+    /// Omit book_title of annotations nested in books in books-json format
+    #[clap(long)]
+    json_compact_books: bool,
+
+    /// Indent JSON (json, books-json, book-summary and web-annotation formats) with given number
+    /// of spaces, 0 is compact JSON
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    json_indent: usize,
+
+    /// Omit header row in CSV format, eg. when appending to an existing file
+    #[clap(long)]
+    no_header: bool,
+
+    /// Reference time used instead of current time (RFC3339)
+    #[clap(long, hide = true, value_parser = parse_rfc3339)]
+    now: Option<DateTime<Utc>>,
+}
+
+impl Args {
+    /// Fails if `--update` is combined with filters selecting only some of the new annotations
+    ///
+    /// Sync time is global, so annotations filtered out would be marked as synced and never
+    /// exported.
+    fn check_update_filters(&self) -> Result<()> {
+        if !self.update || self.read_only {
+            return Ok(());
+        }
+        let filters = [
+            ("--book", !self.book.is_empty()),
+            ("--ignore-book", !self.ignore_book.is_empty()),
+            ("--book-file", self.book_file.is_some()),
+            ("--book-glob", !self.book_glob.is_empty()),
+            ("--book-id", !self.book_id.is_empty()),
+            ("--fuzzy-book", self.fuzzy_book.is_some()),
+            ("--last-opened-book", self.last_opened_book),
+            ("--last-books", self.last_books.is_some()),
+            ("--color", !self.color.is_empty()),
+            ("--with-notes", self.with_notes),
+            ("--favorites-only", self.favorites_only),
+            ("--author", !self.author.is_empty()),
+            ("--finished", self.finished),
+            ("--unfinished", self.unfinished),
+            ("--book-added-since", self.book_added_since.is_some()),
+            ("--with-cover", self.with_cover),
+            ("--only-books-with-notes", self.only_books_with_notes),
+            ("--min-book-highlights", self.min_book_highlights.is_some()),
+        ];
+        match filters.iter().find(|(_, set)| *set) {
+            Some((flag, _)) => Err(Errors::FilteredUpdate(flag).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Timezone of dates in given format
+    ///
+    /// `--tz` and `--utc` win over defaults of formats listed in the `timezones` file in the
+    /// state dir, one `format = offset` pair per line (`local` for the system timezone).
+    fn timezone(&self, format: OutputFormat) -> Result<Timezone> {
+        match self.tz {
+            Some(offset) => return Ok(Timezone::Fixed(offset)),
+            None if self.utc => {
+                let utc = FixedOffset::east_opt(0).expect("Invalid offset");
+                return Ok(Timezone::Fixed(utc));
+            }
+            None => {}
+        }
+        let Some(name) = format.to_possible_value().map(|v| v.get_name().to_string()) else {
+            return Ok(Timezone::Local);
+        };
+        let timezones_file = state_dir()?.join("timezones");
+        let content = fs::read_to_string(&timezones_file).unwrap_or_default();
+        let timezone = content
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| l.split_once('='))
+            .find(|(format, _)| format.trim() == name)
+            .map(|(_, timezone)| timezone.trim());
+        match timezone {
+            None => Ok(Timezone::Local),
+            Some(timezone) if timezone.eq_ignore_ascii_case("local") => Ok(Timezone::Local),
+            Some(timezone) => {
+                let offset = parse_offset(timezone)
+                    .context(Errors::InvalidFormatTimezone(name, timezones_file))?;
+                Ok(Timezone::Fixed(offset))
+            }
+        }
+    }
+
+    /// Output format taking into account shortcut flags (`-j`, `-t`, `--obsidian`)
+    fn output_format(&self) -> OutputFormat {
+        if self.json {
+            OutputFormat::Json
+        } else if self.table {
+            OutputFormat::Table
+        } else if self.obsidian {
+            OutputFormat::Obsidian
+        } else if self.yaml {
+            OutputFormat::Yaml
+        } else if self.mermaid {
+            OutputFormat::Mermaid
+        } else if self.dot {
+            OutputFormat::Dot
+        } else if self.preview {
+            OutputFormat::Preview
+        } else if self.books_jsonl {
+            OutputFormat::BooksJsonl
+        } else if self.web_annotation {
+            OutputFormat::WebAnnotation
+        } else if self.ics {
+            OutputFormat::Ics
+        } else if self.notion {
+            OutputFormat::Notion
+        } else if self.aggregate_notes {
+            OutputFormat::Notes
+        } else if self.group_by_tag {
+            OutputFormat::Tags
+        } else if self.hugo {
+            OutputFormat::Hugo
+        } else if self.jekyll {
+            OutputFormat::Jekyll
+        } else {
+            self.format
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Serve annotations as JSON over HTTP at http://127.0.0.1:<PORT>/annotations (see README)
+    Serve {
+        /// Port to listen on (only local connections are accepted)
+        #[clap(long, default_value = "8080")]
+        port: u16,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    /// Logseq outline with book pages as parent blocks
+    Logseq,
+    /// Obsidian markdown with quotes grouped by book
+    Obsidian,
+    /// JSON array of annotations
+    Json,
+    /// JSON array of books with book metadata and nested annotations
+    BooksJson,
+    /// Same as books-json, but every book is a separate JSON object on its own line
+    BooksJsonl,
+    /// YAML list of annotations, same fields as in JSON
+    Yaml,
+    /// JSON array of books with all highlights of a book joined into a single text
+    BookSummary,
+    /// Terminal table
+    Table,
+    /// Mermaid timeline with number of highlights of every book by month
+    Mermaid,
+    /// CSV with a header row, one annotation per line
+    Csv,
+    /// Standalone HTML page with quotes grouped by book
+    Html,
+    /// Hugo post with front matter for every book (requires --output-dir)
+    Hugo,
+    /// Jekyll post with front matter for every book (requires --output-dir)
+    Jekyll,
+    /// CSV for Notion database import (Title, Author, Highlight, Note, Date, Color)
+    Notion,
+    /// One line per annotation with book title and beginning of the highlight
+    Preview,
+    /// GraphViz graph of books, their highlights and notes
+    Dot,
+    /// Markdown document with notes of every book in reading order, highlights are skipped
+    Notes,
+    /// Markdown document with annotations under every #hashtag of their notes
+    Tags,
+    /// vCard per book (named after the author) with highlights in the NOTE field
+    Vcard,
+    /// iCalendar with an all-day event for every highlight on the day it was created
+    Ics,
+    /// W3C Web Annotation JSON-LD, a collection of annotations per book
+    WebAnnotation,
+}
+
+impl OutputFormat {
+    fn render(
+        self,
+        annotations: Vec<Annotation>,
+        books: &HashMap<String, Book>,
+        options: &format::Options,
+    ) -> String {
+        match self {
+            OutputFormat::Logseq => format::Logseq(annotations, options).to_string(),
+            OutputFormat::Obsidian => format::Obsidian(annotations, options).to_string(),
+            OutputFormat::Json => format::Json(annotations, options).to_string(),
+            OutputFormat::BooksJson => format::BooksJson(annotations, books, options).to_string(),
+            OutputFormat::BooksJsonl => format::BooksJsonl(annotations, books, options).to_string(),
+            OutputFormat::BookSummary => format::BookSummary(annotations, options).to_string(),
+            OutputFormat::Table => format::Table(annotations, options).to_string(),
+            OutputFormat::Mermaid => format::Mermaid(annotations, options).to_string(),
+            OutputFormat::Yaml => format::Yaml(annotations, options).to_string(),
+            OutputFormat::Csv => format::Csv(annotations, options).to_string(),
+            OutputFormat::Html => format::Html(annotations, options).to_string(),
+            OutputFormat::Hugo => {
+                format::Post(annotations, options, format::Site::Hugo).to_string()
+            }
+            OutputFormat::Notion => format::Notion(annotations, options).to_string(),
+            OutputFormat::Preview => format::Preview(annotations, options).to_string(),
+            OutputFormat::Dot => format::Dot(annotations, options).to_string(),
+            OutputFormat::Notes => format::Notes(annotations, options).to_string(),
+            OutputFormat::Tags => format::Tags(annotations, options).to_string(),
+            OutputFormat::Vcard => format::Vcard(annotations, options).to_string(),
+            OutputFormat::Ics => format::Ics(annotations, options).to_string(),
+            OutputFormat::WebAnnotation => format::WebAnnotation(annotations, options).to_string(),
+            OutputFormat::Jekyll => {
+                format::Post(annotations, options, format::Site::Jekyll).to_string()
+            }
+        }
+    }
+
+    /// File extension for the files written in this format
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Logseq | OutputFormat::Obsidian => "md",
+            OutputFormat::Hugo | OutputFormat::Jekyll => "md",
+            OutputFormat::Notes | OutputFormat::Tags => "md",
+            OutputFormat::Json | OutputFormat::BooksJson | OutputFormat::BookSummary => "json",
+            OutputFormat::BooksJsonl => "jsonl",
+            OutputFormat::Yaml => "yaml",
+            OutputFormat::Table | OutputFormat::Preview => "txt",
+            OutputFormat::Mermaid => "mmd",
+            OutputFormat::Dot => "dot",
+            OutputFormat::WebAnnotation => "jsonld",
+            OutputFormat::Vcard => "vcf",
+            OutputFormat::Ics => "ics",
+            OutputFormat::Csv | OutputFormat::Notion => "csv",
+            OutputFormat::Html => "html",
+        }
+    }
+
+    /// Formats writing JSON, reports (eg. `--stats`) are printed as JSON for them
+    fn is_json(self) -> bool {
+        matches!(
+            self,
+            OutputFormat::Json
+                | OutputFormat::BooksJson
+                | OutputFormat::BooksJsonl
+                | OutputFormat::BookSummary
+                | OutputFormat::WebAnnotation
+        )
+    }
+
+    /// Blog post formats, every book is written to a separate file named by [`post_stem`]
+    fn is_post(self) -> bool {
+        matches!(self, OutputFormat::Hugo | OutputFormat::Jekyll)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CountBy {
+    Book,
+    Color,
+    /// Month the annotation was created
+    Month,
+    /// Language of the book
+    Language,
+}
+
+/// Order of books in output
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum BookOrder {
+    /// Alphabetically by title
+    Alpha,
+    /// Books with more highlights first
+    Count,
+    /// Recently opened books first
+    Recent,
+    /// By time of the first highlight of the book, earliest first
+    Added,
+}
+
+/// Order of annotations in output
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum AnnotationOrder {
+    /// By creation time, earliest first
+    Time,
+    /// Longest highlights (by number of characters) first
+    Length,
+}
+
+/// Numbering of highlights with `--numbered`
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum NumberScope {
+    /// Highlights are numbered across all books
+    Global,
+    /// Numbering starts from 1 in every book
+    Book,
+}
+
+/// Position of a note relative to the quote in Logseq format
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum NotePosition {
+    /// Note is a parent block with the quote nested beneath it
+    Above,
+    /// Quote is a parent block with the note as a child block
+    Below,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SyncFormat {
+    /// RFC3339 date (eg. 2022-12-24T03:56:40+00:00)
+    Rfc3339,
+    /// Seconds since Unix epoch
+    Epoch,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputEncoding {
+    Utf8,
+    /// UTF-8 with byte order mark
+    Utf8Bom,
+    /// UTF-16 little endian with byte order mark
+    Utf16le,
+}
+
+impl OutputEncoding {
+    fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            OutputEncoding::Utf8 => text.as_bytes().to_vec(),
+            OutputEncoding::Utf8Bom => [b"\xEF\xBB\xBF", text.as_bytes()].concat(),
+            OutputEncoding::Utf16le => ["\u{FEFF}", text]
+                .iter()
+                .flat_map(|s| s.encode_utf16())
+                .flat_map(u16::to_le_bytes)
+                .collect(),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -44,323 +1086,5536 @@ enum Errors {
     #[error("iBooks database not found. Are you sure iBooks is installed?")]
     NoDbFound,
 
-    #[error("Processing annotation")]
-    ContextProcessingAnnotation,
+    #[error("Processing annotation")]
+    ContextProcessingAnnotation,
+
+    #[error("Unable to find program location")]
+    UnableToFindProgramLocation,
+
+    #[error("Unable to write sync-file")]
+    UnableToWriteSyncFile,
+
+    #[error("Unable to write schema fingerprint: {0}")]
+    UnableToWriteSchemaFingerprint(PathBuf),
+
+    #[error(
+        "{0} can't be combined with --update, filtered out annotations would never be exported"
+    )]
+    FilteredUpdate(&'static str),
+
+    #[error("Unable to listen on port {0}")]
+    UnableToListen(u16),
+
+    #[error("No book title matches \"{0}\"")]
+    NoBookMatched(String),
+
+    #[error("Several books match \"{0}\", please be more specific:\n  {1}")]
+    AmbiguousBook(String, String),
+
+    #[error("Sync-file backup not found: {0}")]
+    SyncBackupNotFound(PathBuf),
+
+    #[error("Unable to read sync-file")]
+    UnableToReadSyncFile,
+
+    #[error("Sync-file not found: {0}")]
+    SyncFileNotFound(PathBuf),
+
+    #[error("Unable to read SQL file: {0}")]
+    UnableToReadSqlFile(PathBuf),
+
+    #[error("Query of --sql-file must return 4 columns (selected text, note, timestamp and title), got {0}")]
+    SqlFileColumns(usize),
+
+    #[error("Query of --sql-file must have at most one parameter (last sync time), got {0}")]
+    SqlFileParameters(usize),
+
+    #[error("Reading annotations took longer than {0} seconds (--timeout)")]
+    QueryTimeout(u64),
+
+    #[error("{0} annotations are skipped or malformed (--strict)")]
+    StrictViolations(usize),
+
+    #[error("Unable to read JSON export: {0}")]
+    UnableToReadExport(PathBuf),
+
+    #[error("Unable to write output file: {0}")]
+    UnableToWriteOutput(PathBuf),
+
+    #[error("No book in the library has last opened date")]
+    NoOpenedBooks,
+
+    #[error("Profile {0} is not listed in {1}")]
+    UnknownProfile(String, PathBuf),
+
+    #[error("Invalid timezone of {0} format in {1}")]
+    InvalidFormatTimezone(String, PathBuf),
+
+    #[error("Unable to copy output to clipboard")]
+    UnableToCopyToClipboard,
+
+    #[error("Formatter command failed: {0}")]
+    FormatterCommandFailed(String),
+
+    #[error("Unable to read book file: {0}")]
+    UnableToReadBookFile(PathBuf),
+
+    #[error("Unable to read iOS backup manifest in {0}. Encrypted backups are not supported")]
+    UnableToReadBackupManifest(PathBuf),
+
+    #[error("No annotation database matches {0}")]
+    NoDatabaseMatched(String),
+
+    #[error("Locale {0} is not supported")]
+    UnsupportedLocale(String),
+
+    #[error("Unable to write SQLite database: {0}")]
+    UnableToWriteSqlite(PathBuf),
+
+    #[error("Column {column} not found in table {table}. {}", SCHEMA_HINT)]
+    SchemaColumnMissing { table: String, column: String },
+
+    #[error("Column {column} has unexpected type {column_type}. {}", SCHEMA_HINT)]
+    UnexpectedColumnType { column: String, column_type: String },
+
+    #[error("Unknown field {0}. Valid fields are: {}", ANNOTATION_FIELDS.join(", "))]
+    UnknownField(String),
+
+    #[error("Sync-file version {0} is not supported, please update ibooks-export")]
+    UnsupportedSyncFileVersion(u32),
+
+    #[error("--format {0} writes a file for every book and requires --output-dir")]
+    OutputDirRequired(String),
+
+    #[error("Invalid SOURCE_DATE_EPOCH value: {0}")]
+    InvalidSourceDateEpoch(String),
+}
+
+const DEFAULT_BUNDLE_ID: &str = "com.apple.iBooksX";
+
+const SCHEMA_HINT: &str =
+    "Database schema is not supported, probably due to iBooks version change. \
+    Please, check your iBooks version or file a schema report: \
+    https://github.com/bazhenov/ibooks-highlights-export/issues";
+
+/// Columns of annotation database (`ZAEANNOTATION` table) required for export
+const ANNOTATION_COLUMNS: &[&str] = &[
+    "ZANNOTATIONSELECTEDTEXT",
+    "ZANNOTATIONNOTE",
+    "ZFUTUREPROOFING6",
+    "ZANNOTATIONASSETID",
+    "ZANNOTATIONDELETED",
+    "ZANNOTATIONLOCATION",
+    "ZANNOTATIONSTYLE",
+    "ZANNOTATIONUUID",
+    "ZANNOTATIONCREATIONDATE",
+    "ZANNOTATIONMODIFICATIONDATE",
+    "ZANNOTATIONREPRESENTATIVETEXT",
+];
+
+/// Columns of annotation table present only in some iBooks versions, NULL if missing
+const OPTIONAL_ANNOTATION_COLUMNS: &[&str] = &["ZANNOTATIONISFAVORITE"];
+
+/// Layout of annotation table, detected by [`AnnotationLayout::detect`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AnnotationLayout {
+    /// `ZAEANNOTATION` table with [`ANNOTATION_COLUMNS`]
+    Legacy,
+    /// `ZBKANNOTATION` table of newer macOS versions, columns have no `ANNOTATION` infix
+    /// (eg. `ZSELECTEDTEXT` instead of `ZANNOTATIONSELECTEDTEXT`)
+    BkAnnotation,
+}
+
+impl AnnotationLayout {
+    fn table(self) -> &'static str {
+        match self {
+            Self::Legacy => "ZAEANNOTATION",
+            Self::BkAnnotation => "ZBKANNOTATION",
+        }
+    }
+
+    /// Name of a column of [`ANNOTATION_COLUMNS`] in this layout
+    fn column(self, legacy: &str) -> String {
+        match self {
+            Self::Legacy => legacy.to_string(),
+            Self::BkAnnotation => legacy.replacen("ZANNOTATION", "Z", 1),
+        }
+    }
+
+    fn columns(self) -> Vec<String> {
+        ANNOTATION_COLUMNS.iter().map(|c| self.column(c)).collect()
+    }
+
+    /// Layout of annotation table of the schema, `None` if there is no annotation table
+    ///
+    /// Legacy table is preferred if database has both tables.
+    fn detect(connection: &Connection, schema: &str) -> Result<Option<Self>> {
+        for layout in [Self::Legacy, Self::BkAnnotation] {
+            if !table_columns(connection, schema, layout.table())?.is_empty() {
+                debug!("{} annotation table: {}", schema, layout.table());
+                return Ok(Some(layout));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Columns of library database (`ZBKLIBRARYASSET` table) required for export
+const LIBRARY_COLUMNS: &[&str] = &["ZASSETID", "ZTITLE", "ZAUTHOR"];
+
+/// Names of [`Annotation`] fields in JSON output
+const ANNOTATION_FIELDS: &[&str] = &[
+    "selected_text",
+    "note",
+    "anotation_time",
+    "created_at",
+    "modified_at",
+    "book_title",
+    "author",
+    "asset_id",
+    "location",
+    "location_start",
+    "location_end",
+    "color",
+    "annotation_type",
+    "uuid",
+    "context",
+    "word_count",
+    "favorited",
+    "slug",
+];
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Annotation {
+    selected_text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    anotation_time: DateTime<Utc>,
+    /// Time the highlight was created
+    created_at: DateTime<Utc>,
+    /// Time the highlight (or its note) was last edited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified_at: Option<DateTime<Utc>>,
+    book_title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+    asset_id: String,
+    /// EPUB CFI range of the highlight as stored by iBooks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+    /// Start of the location range, best-effort parse of `location`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location_start: Option<String>,
+    /// End of the location range, best-effort parse of `location`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location_end: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<HighlightColor>,
+    /// `highlight`, `underline` or `note`, see [`annotation_type`]
+    #[serde(default)]
+    annotation_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+    /// Text around the highlight stored by iBooks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<String>,
+    /// Number of words in selected text
+    #[serde(default)]
+    word_count: usize,
+    /// Highlight is flagged as favorite, `None` if iBooks version doesn't support flagging
+    #[serde(skip_serializing_if = "Option::is_none")]
+    favorited: Option<bool>,
+    /// Stable id of the highlight usable as HTML anchor, see [`annotation_slug`]
+    #[serde(default)]
+    slug: String,
+    /// Length of selected text in the database in characters, for `--debug-lengths`
+    #[serde(skip)]
+    raw_length: Option<usize>,
+    /// Reference number of the highlight, see `--numbered`
+    #[serde(skip)]
+    number: Option<usize>,
+}
+
+impl Annotation {
+    /// iBooks deep link to the book, pointing to the highlight location if it's known
+    fn url(&self) -> String {
+        match &self.location {
+            Some(location) => format!("ibooks://assetid/{}#{}", self.asset_id, location),
+            None => format!("ibooks://assetid/{}", self.asset_id),
+        }
+    }
+
+    /// Shell command opening the highlight in iBooks
+    ///
+    /// URL is single quoted, so `!` of the CFI location is not expanded by the shell.
+    fn open_command(&self) -> String {
+        format!("open '{}'", self.url())
+    }
+}
+
+/// Highlight color selected in iBooks
+#[derive(Serialize, Deserialize, ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum HighlightColor {
+    Green,
+    Blue,
+    Yellow,
+    Pink,
+    Purple,
+}
+
+impl HighlightColor {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Green => "green",
+            Self::Blue => "blue",
+            Self::Yellow => "yellow",
+            Self::Pink => "pink",
+            Self::Purple => "purple",
+        }
+    }
+
+    /// Color for a `ZANNOTATIONSTYLE` value. Style 0 is an underline, which has no color
+    fn from_style(style: i64) -> Option<Self> {
+        match style {
+            1 => Some(Self::Green),
+            2 => Some(Self::Blue),
+            3 => Some(Self::Yellow),
+            4 => Some(Self::Pink),
+            5 => Some(Self::Purple),
+            _ => None,
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let mut args = Args::parse();
+
+    if args.format_help {
+        print_format_help();
+        return Ok(());
+    }
+
+    if args.list_formats {
+        let names = OutputFormat::value_variants()
+            .iter()
+            .filter_map(|f| f.to_possible_value())
+            .map(|v| v.get_name().to_string())
+            .collect::<Vec<_>>();
+        if args.json {
+            println!("{}", serde_json::to_string(&names)?);
+        } else {
+            for name in names {
+                println!("{}", name);
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(Action::Serve { port }) = args.action {
+        return serve::run(&args, port);
+    }
+
+    if args.watch {
+        args.update = true;
+        args.check_update_filters()?;
+        return watch(&args);
+    }
+    export(&args)
+}
+
+/// Exports annotations every time annotation databases are changed
+///
+/// Changes are debounced, so export runs only after `--watch-interval` seconds without changes
+/// (iBooks writes to the database several times when a highlight is added).
+fn watch(args: &Args) -> Result<()> {
+    let (annotation_dbs, _) = databases(args)?;
+    let dirs = annotation_dbs
+        .iter()
+        .filter_map(|db| db.parent().map(Path::to_path_buf))
+        .collect::<HashSet<_>>();
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for dir in &dirs {
+        debug!("Watching {:?}", dir);
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    let interval = std::time::Duration::from_secs(args.watch_interval);
+    // Opening database for reading emits events too, only changes by iBooks are interesting
+    let is_change = |event: &notify::Result<notify::Event>| {
+        event.as_ref().is_ok_and(|e| {
+            !e.kind.is_access()
+                && !e
+                    .paths
+                    .iter()
+                    .all(|p| p.to_string_lossy().ends_with("-shm"))
+        })
+    };
+    loop {
+        if let Err(e) = export(args) {
+            eprintln!("Error: {:?}", e);
+        }
+        while !is_change(&rx.recv()?) {}
+        while rx.recv_timeout(interval).is_ok() {}
+    }
+}
+
+fn export(args: &Args) -> Result<()> {
+    if let Some(field) = args
+        .fields
+        .iter()
+        .find(|f| !ANNOTATION_FIELDS.contains(&f.as_str()))
+    {
+        return Err(Errors::UnknownField(field.clone()).into());
+    }
+
+    if args.restore_sync {
+        let last_sync_file = LastSyncFile::find(args.profile.as_deref())?;
+        last_sync_file.restore()?;
+        match last_sync_file.read()? {
+            Some(state) => println!("Sync time restored to {}", local_time(state.time)),
+            None => println!("Sync-file restored, nothing is synced yet"),
+        }
+        return Ok(());
+    }
+
+    if args.read_only {
+        let ignored = [
+            ("--update", args.update),
+            ("--output", args.output.is_some()),
+            ("--split", !args.split.is_empty()),
+            ("--sqlite-out", args.sqlite_out.is_some()),
+            ("--export-all", args.export_all.is_some()),
+            ("--output-dir", args.output_dir.is_some()),
+            ("--tar", args.tar),
+            ("--clipboard", args.clipboard),
+        ];
+        for (flag, _) in ignored.iter().filter(|(_, set)| *set) {
+            eprintln!("Warning: {} is ignored because of --read-only", flag);
+        }
+    }
+
+    args.check_update_filters()?;
+
+    let now = reference_time(args.now)?;
+    debug!("Reference time: {}", now);
+
+    let (annotation_dbs, library_db) = databases(args)?;
+    if args.library_dump {
+        let library_db = library_db.ok_or(Errors::NoDbFound)?;
+        println!("{}", format::LibraryJson(&read_library(&library_db)?));
+        return Ok(());
+    }
+    let mut annotation_dbs = annotation_dbs.into_iter();
+    let (annotation_db, library_db) = annotation_dbs
+        .next()
+        .zip(library_db)
+        .ok_or(Errors::NoDbFound)?;
+    let secondary_dbs = annotation_dbs.collect::<Vec<_>>();
+
+    debug!("Library database location: {:?}", &library_db);
+    debug!("Annotation database location: {:?}", &annotation_db);
+    for db in &secondary_dbs {
+        debug!("Secondary annotation database location: {:?}", db);
+    }
+
+    let last_sync_file = LastSyncFile::find(args.profile.as_deref())?;
+    debug!("Last sync file: {:?}", last_sync_file.0);
+    let fingerprint = SchemaFingerprint::find(args.profile.as_deref())?;
+    let merged_sync_files = args
+        .merge_sync
+        .iter()
+        .cloned()
+        .map(LastSyncFile)
+        .collect::<Vec<_>>();
+    let sync_files = || [&last_sync_file].into_iter().chain(&merged_sync_files);
+
+    if args.init {
+        open_databases(&annotation_db, &secondary_dbs, &library_db, false)?;
+        let created = last_sync_file.init()?;
+        fingerprint.check(
+            &annotation_schema(&annotation_db)?,
+            args.accept_schema,
+            true,
+        )?;
+        println!("Annotation database: {}", annotation_db.display());
+        println!("Library database:    {}", library_db.display());
+        println!(
+            "Sync-file:           {}{}",
+            last_sync_file.0.display(),
+            if created { " (created)" } else { "" }
+        );
+        println!();
+        println!(
+            "Run `ibooks-export --update` to export all highlights and remember the sync time."
+        );
+        println!("Subsequent runs with `--update` will export only new highlights.");
+        return Ok(());
+    }
+
+    if args.validate {
+        open_databases(&annotation_db, &secondary_dbs, &library_db, false)?;
+        last_sync_file.read()?;
+        println!("OK");
+        return Ok(());
+    }
+
+    if args.verify_sync {
+        let query = AnnotationQuery {
+            include_deleted: args.include_deleted,
+            timeout: args.timeout,
+            timings: args.profile_timings,
+            ..AnnotationQuery::default()
+        };
+        let annotations = read_annotations(&annotation_db, &secondary_dbs, &library_db, &query)?;
+        let drift = SyncDrift::new(last_sync_file.read()?.map(|s| s.time), &annotations);
+        if args.output_format().is_json() {
+            println!("{}", format::SyncDriftJson(&drift));
+        } else {
+            print!("{}", format::SyncDriftText(&drift));
+        }
+        return Ok(());
+    }
+
+    // Edits report is independent of the sync state
+    let last_sync = if args.all || args.modified_since.is_some() || args.active_since.is_some() {
+        None
+    } else if let Some(path) = &args.since_sync_of {
+        if !path.exists() {
+            return Err(Errors::SyncFileNotFound(path.clone()).into());
+        }
+        LastSyncFile(path.clone()).read()?.map(|s| s.time)
+    } else {
+        let mut last_sync = last_sync_file.read()?.map(|s| s.time);
+        for file in &merged_sync_files {
+            // Never synced file (`None`) is the earliest, so nothing is missed
+            last_sync = last_sync.min(file.read()?.map(|s| s.time));
+        }
+        last_sync
+    };
+    debug!("Last sync date: {:?}", last_sync);
+
+    if let Some(last_sync) = last_sync.filter(|_| args.skip_if_unchanged) {
+        let databases = [&annotation_db].into_iter().chain(&secondary_dbs);
+        // Databases with unknown modification time are considered modified
+        let modified = databases
+            .map(|db| last_modified(db))
+            .collect::<Option<Vec<_>>>();
+        let modified = modified.and_then(|times| times.into_iter().max());
+        debug!("Annotation databases last modified: {:?}", modified);
+        if modified.is_some_and(|modified| modified <= last_sync) {
+            eprintln!("No changes since last sync");
+            return Ok(());
+        }
+    }
+
+    let listed_books = match &args.book_file {
+        Some(path) => read_book_file(path)?,
+        None => vec![],
+    };
+    let book_filter = BookFilter::new(
+        &args.book,
+        &listed_books,
+        &args.ignore_book,
+        &args.book_glob,
+    );
+    let mut book_ids = args.book_id.clone();
+    if args.last_opened_book {
+        let books = read_books(&library_db)?;
+        let (asset_id, _) = books
+            .iter()
+            .filter_map(|(id, b)| Some((id, b.last_opened?)))
+            .max_by_key(|(_, last_opened)| *last_opened)
+            .ok_or(Errors::NoOpenedBooks)?;
+        debug!("Last opened book: {}", asset_id);
+        book_ids.push(asset_id.clone());
+    }
+    if let Some(n) = args.last_books {
+        let books = read_books(&library_db)?;
+        let mut recent = books
+            .iter()
+            .filter_map(|(id, b)| Some((id, b.last_opened.max(b.date_added)?)))
+            .collect::<Vec<_>>();
+        if recent.is_empty() {
+            return Err(Errors::NoOpenedBooks.into());
+        }
+        recent.sort_by_key(|(_, time)| Reverse(*time));
+        for (asset_id, _) in recent.into_iter().take(n as usize) {
+            debug!("Recent book: {}", asset_id);
+            book_ids.push(asset_id.clone());
+        }
+    }
+    if let Some(query) = &args.fuzzy_book {
+        let (asset_id, title) = fuzzy_find_book(&read_book_titles(&library_db)?, query)?;
+        eprintln!("Matched book: {}", title);
+        book_ids.push(asset_id);
+    }
+    let query = AnnotationQuery {
+        created_after: last_sync,
+        modified_after: args.modified_since,
+        active_after: args.active_since,
+        include_deleted: args.include_deleted,
+        book_ids,
+        explain: args.explain,
+        timeout: args.timeout,
+        timings: args.profile_timings,
+        strict: args.strict,
+        skip_errors: args.skip_errors,
+    };
+    if args.estimate {
+        let count = estimate_annotations(&annotation_db, &secondary_dbs, &library_db, &query)?;
+        println!("{}", count);
+        return Ok(());
+    }
+    if args.orphans {
+        let orphans = read_orphans(&annotation_db, &secondary_dbs, &library_db, &query)?;
+        if args.output_format().is_json() {
+            println!("{}", format::OrphansJson(&orphans));
+        } else {
+            print!("{}", format::OrphansText(&orphans));
+        }
+        return Ok(());
+    }
+
+    // Fingerprint is state too, so it's first stored only by runs updating the sync-file
+    fingerprint.check(
+        &annotation_schema(&annotation_db)?,
+        args.accept_schema && !args.read_only,
+        args.update && !args.read_only,
+    )?;
+    let annotations = match &args.sql_file {
+        Some(path) => {
+            let sql =
+                fs::read_to_string(path).context(Errors::UnableToReadSqlFile(path.clone()))?;
+            read_annotations_with_sql(&annotation_db, &library_db, &sql, &query)
+        }
+        None => read_annotations(&annotation_db, &secondary_dbs, &library_db, &query),
+    };
+    let mut annotations = annotations.map_err(|e| match args.timeout {
+        Some(secs) if is_interrupted(&e) => Errors::QueryTimeout(secs).into(),
+        _ => e,
+    })?;
+    // Single bogus timestamp would move sync time far into the future (or the past)
+    let max_date = args.max_date.unwrap_or(now + Duration::days(1));
+    let mut problems = vec![];
+    annotations.retain(|a| {
+        let sane = (args.min_date..=max_date).contains(&a.anotation_time);
+        if !sane && args.strict {
+            let reason = format!("invalid date {}", a.anotation_time);
+            problems.push((annotation_id(a.uuid.as_deref(), &a.asset_id), reason));
+        } else if !sane {
+            warn!(
+                "Skipping annotation {} with invalid date {}",
+                annotation_id(a.uuid.as_deref(), &a.asset_id),
+                a.anotation_time
+            );
+        }
+        sane
+    });
+    check_strict(&problems)?;
+    if !secondary_dbs.is_empty() {
+        let duplicates;
+        (annotations, duplicates) = dedupe_by_uuid(annotations);
+        if !args.annotation_db.is_empty() {
+            eprintln!(
+                "Merged {} annotation databases, {} duplicates collapsed",
+                secondary_dbs.len() + 1,
+                duplicates
+            );
+        }
+    }
+    for a in annotations.iter_mut() {
+        if a.book_title.is_empty() {
+            a.book_title = args.unknown_book.clone();
+        }
+        if args.snap_to_sentence {
+            if let Some(text) = a
+                .context
+                .as_deref()
+                .and_then(|c| snap_to_sentence(&a.selected_text, c))
+            {
+                a.word_count = word_count(&text);
+                a.selected_text = text;
+            }
+        }
+        if args.trim_ellipsis {
+            a.selected_text = trim_ellipsis(&a.selected_text);
+            a.word_count = word_count(&a.selected_text);
+        }
+        if args.ascii_only {
+            a.selected_text = to_ascii(&a.selected_text);
+            a.note = a.note.as_deref().map(to_ascii);
+        }
+    }
+    let annotation_filter = AnnotationFilter {
+        colors: args.color.clone(),
+        with_notes: args.with_notes,
+        favorites_only: args.favorites_only,
+        authors: args.author.iter().map(|a| a.to_lowercase()).collect(),
+    };
+    annotations.retain(|a| {
+        book_filter.matches(&a.book_title, &a.asset_id) && annotation_filter.matches(a)
+    });
+    if args.finished || args.unfinished {
+        let books = read_books(&library_db)?;
+        annotations.retain(|a| {
+            let finished = books.get(&a.asset_id).and_then(|b| b.finished);
+            finished == Some(args.finished)
+        });
+    }
+    if let Some(since) = args.book_added_since {
+        let books = read_books(&library_db)?;
+        annotations.retain(|a| {
+            let added = books.get(&a.asset_id).and_then(|b| b.date_added);
+            added.is_some_and(|added| added > since)
+        });
+    }
+    if args.with_cover {
+        let books = read_books(&library_db)?;
+        annotations.retain(|a| books.get(&a.asset_id).is_some_and(|b| b.cover.is_some()));
+    }
+    if args.only_books_with_notes {
+        // Book level filter, so it's applied after annotation filters
+        let noted_books = annotations
+            .iter()
+            .filter(|a| a.note.as_deref().is_some_and(|n| !n.trim().is_empty()))
+            .map(|a| a.asset_id.clone())
+            .collect::<HashSet<_>>();
+        annotations.retain(|a| noted_books.contains(&a.asset_id));
+    }
+    if let Some(min) = args.min_book_highlights {
+        let mut counts = HashMap::<_, usize>::new();
+        for a in &annotations {
+            *counts.entry(a.asset_id.clone()).or_default() += 1;
+        }
+        annotations.retain(|a| counts[&a.asset_id] >= min as usize);
+    }
+    let synced = annotations.iter().map(SyncState::from).collect::<Vec<_>>();
+    // Creation time of the earliest annotation left out by --max-per-book or paging
+    let mut first_dropped = None;
+
+    if args.dedupe_by_note {
+        annotations = dedupe_by_note(annotations);
+    }
+
+    if args.merge_consecutive {
+        let window = Duration::seconds(args.merge_window.into());
+        annotations = merge_consecutive(annotations, window);
+    }
+
+    if let Some(max) = args.max_per_book {
+        let dropped;
+        (annotations, dropped) = limit_per_book(annotations, max as usize);
+        first_dropped = dropped.iter().map(|a| a.anotation_time).min();
+        let mut truncated = BTreeMap::<_, usize>::new();
+        for a in &dropped {
+            *truncated.entry(&a.book_title).or_default() += 1;
+        }
+        for (book, count) in truncated {
+            eprintln!(
+                "{}: {} annotations truncated by --max-per-book",
+                book, count
+            );
+        }
+    }
+
+    if let Some(n) = args.sample {
+        let mut rng = match args.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        annotations.shuffle(&mut rng);
+        annotations.truncate(n);
+        annotations.sort_by_key(|a| a.anotation_time);
+    }
+
+    if args.sort == AnnotationOrder::Length {
+        sort_by_length(&mut annotations);
+    }
+
+    if args.offset.is_some() || args.limit.is_some() {
+        let offset = args.offset.unwrap_or(0) as usize;
+        let limit = args.limit.map(|l| l as usize);
+        let first_skipped;
+        (annotations, first_skipped) = paginate(annotations, offset, limit);
+        first_dropped = first_dropped.into_iter().chain(first_skipped).min();
+    }
+
+    // Sync time never passes annotations left out, so they are exported by one of the next runs
+    let synced = synced
+        .into_iter()
+        .filter(|s| first_dropped.is_none_or(|t| s.time < t))
+        .collect::<Vec<_>>();
+    let synced_count = synced.len();
+    let new_sync_state = synced.into_iter().max_by_key(|s| s.time);
+
+    if let Some(path) = &args.combine_with {
+        let mut combined = read_json_export(path)?;
+        combined.append(&mut annotations);
+        // Newly read annotations go last, so they win over exported ones with the same UUID
+        (annotations, _) = dedupe_by_uuid(combined);
+        annotations.sort_by_key(|a| a.anotation_time);
+        if args.sort == AnnotationOrder::Length {
+            sort_by_length(&mut annotations);
+        }
+    }
+
+    if let Some(count_by) = args.count_by {
+        let books = match count_by {
+            CountBy::Language => read_books(&library_db)?,
+            _ => HashMap::new(),
+        };
+        let timezone = args.timezone(args.output_format())?;
+        let counts = count_annotations(&annotations, count_by, &books, timezone);
+        if args.output_format().is_json() {
+            println!("{}", format::CountsJson(counts));
+        } else {
+            print!("{}", format::Counts(counts));
+        }
+        return Ok(());
+    }
+
+    if args.stats {
+        let stats = Stats::new(&annotations);
+        if args.output_format().is_json() {
+            println!("{}", format::StatsJson(&stats));
+        } else {
+            print!("{}", format::StatsText(&stats));
+        }
+        return Ok(());
+    }
+
+    if let Some(bins) = args.density {
+        let density = Density::new(&annotations, bins as usize);
+        if args.output_format().is_json() {
+            println!("{}", format::DensityJson(&density));
+        } else {
+            print!("{}", format::DensityText(&density));
+        }
+        return Ok(());
+    }
+
+    if args.list_books {
+        let mut books = BTreeMap::new();
+        for a in &annotations {
+            books.entry(&a.book_title).or_insert(a.author.as_deref());
+        }
+        print!(
+            "{}",
+            format::BookList(books.into_iter().collect(), args.raw)
+        );
+        return Ok(());
+    }
+
+    if args.interactive {
+        if let Some(state) = tui::run(&annotations)? {
+            if args.no_update || args.read_only {
+                eprintln!("Sync point is not saved because of --no-update or --read-only");
+            } else {
+                for file in sync_files() {
+                    if args.backup_sync {
+                        file.backup()?;
+                    }
+                    debug!("Updating last sync time of {:?}: {}", file.0, state.time);
+                    file.update(&state, args.sync_format)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    if args.debug_lengths {
+        for a in &annotations {
+            let Some(raw_length) = a.raw_length else {
+                continue;
+            };
+            eprintln!(
+                "Annotation {}: {} characters in the database, {} characters exported",
+                annotation_id(a.uuid.as_deref(), &a.asset_id),
+                raw_length,
+                a.selected_text.chars().count()
+            );
+        }
+    }
+
+    let format = args.output_format();
+    if let OutputFormat::Notes = format {
+        // Otherwise books without notes are written to --output-dir as empty documents
+        annotations.retain(format::has_note);
+    }
+    let books = match (format, args.book_order) {
+        (OutputFormat::BooksJson | OutputFormat::BooksJsonl, _) | (_, BookOrder::Recent) => {
+            read_books(&library_db)?
+        }
+        _ if args.with_bibtex => read_books(&library_db)?,
+        (OutputFormat::Html, _) => read_books(&library_db)?,
+        _ => HashMap::new(),
+    };
+    let last_opened = books
+        .iter()
+        .filter_map(|(id, b)| Some((id.clone(), b.last_opened?)))
+        .collect();
+    let covers = books
+        .iter()
+        .filter_map(|(id, b)| Some((id.clone(), b.cover.clone()?)))
+        .collect();
+    let mut format_options = format::Options {
+        empty_text: args.empty_text.clone(),
+        relative_dates: args.relative_dates,
+        now,
+        fields: args.fields.clone(),
+        color_rows: args.color_rows && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+        summary_separator: args.summary_separator.clone(),
+        book_order: args.book_order,
+        show_lengths: args.show_lengths,
+        group_by_color: args.group_by_color_within_book,
+        last_opened,
+        covers,
+        note_position: args.note_position,
+        with_open_command: args.with_open_command,
+        compact_logseq: args.compact_logseq,
+        logseq_color_property: args.logseq_color_property,
+        bibtex: args.with_bibtex.then(|| books.clone()),
+        timezone: args.timezone(format)?,
+        epoch_timestamps: args.epoch_timestamps,
+        include_null_notes: args.include_null_notes,
+        envelope: args.envelope,
+        group_by_month: args.group_by_month,
+        json_compact_books: args.json_compact_books,
+        json_indent: args.json_indent,
+        no_header: args.no_header,
+        collapse_whitespace: args.collapse_whitespace,
+        with_context: args.with_context,
+        color_legend: args.color_legend,
+        color_labels: args.color_label.clone(),
+        block_prefix: args.block_prefix.clone(),
+        block_suffix: args.block_suffix.clone(),
+        note_prefix: args.note_prefix.clone(),
+        escape_markdown: args.escape_markdown,
+        annotation_separator: args.annotation_separator.clone(),
+        book_separator: args.book_separator.clone(),
+        heading_format: args.heading_format.clone(),
+        logseq_parent: args.logseq_parent.clone(),
+        post_tags: args.post_tag.clone(),
+        notes_with_quotes: args.notes_with_quotes,
+        collapse_identical: args.collapse_identical,
+        locale: args
+            .locale
+            .as_deref()
+            .map(format::Locale::new)
+            .transpose()?,
+    };
+    if args.numbered {
+        number_annotations(&mut annotations, &format_options, args.number_scope);
+    }
+    let encoding = args.output_encoding;
+    let timings = args.profile_timings;
+    let render = |annotations| -> Result<String> {
+        let started = Instant::now();
+        let output = match &args.formatter_cmd {
+            Some(cmd) => {
+                let json = OutputFormat::Json.render(annotations, &books, &format_options);
+                run_formatter(cmd, &json)?
+            }
+            None => format.render(annotations, &books, &format_options),
+        };
+        report_timing(timings, "format", started);
+        Ok(output)
+    };
+    let write = |path: &Path, annotations| -> Result<()> {
+        let output = render(annotations)?;
+        let started = Instant::now();
+        if !write_output(path, &output, encoding)? {
+            eprintln!("{}: unchanged", path.display());
+        }
+        report_timing(timings, "write", started);
+        Ok(())
+    };
+    let print = |annotations| -> Result<()> {
+        let output = render(annotations)?;
+        let started = Instant::now();
+        print_output(&output, encoding)?;
+        report_timing(timings, "write", started);
+        Ok(())
+    };
+    if format.is_post() && ((args.output_dir.is_none() && !args.tar) || args.read_only) {
+        let name = format.to_possible_value().map(|v| v.get_name().to_string());
+        return Err(Errors::OutputDirRequired(name.unwrap_or_default()).into());
+    }
+    if args.read_only {
+        print(annotations)?;
+    } else if let [notes_path, highlights_path] = args.split.as_slice() {
+        let (notes, highlights) = annotations.into_iter().partition(|a| a.note.is_some());
+        write(notes_path, notes)?;
+        write(highlights_path, highlights)?;
+    } else if let Some(dir) = &args.export_all {
+        fs::create_dir_all(dir).context(Errors::UnableToWriteOutput(dir.clone()))?;
+        for (name, format) in EXPORT_ALL_FORMATS {
+            format_options.timezone = args.timezone(format)?;
+            let output = format.render(annotations.clone(), &books, &format_options);
+            let path = dir.join(name);
+            if !write_output(&path, &output, encoding)? {
+                eprintln!("{}: unchanged", path.display());
+            }
+        }
+    } else if let Some(dir) = &args.output_dir {
+        fs::create_dir_all(dir).context(Errors::UnableToWriteOutput(dir.clone()))?;
+        let mut file_names = HashSet::new();
+        let mut index = vec![];
+        for book in format::group_by_book(&annotations, &format_options) {
+            let (title, asset_id) = (&book[0].book_title, &book[0].asset_id);
+            let mut stem = if format.is_post() {
+                post_stem(&book, format_options.timezone)
+            } else {
+                file_stem(title)
+            };
+            // Different books may have the same title
+            if !file_names.insert(stem.clone()) {
+                stem = if format.is_post() {
+                    format!("{}-{}", stem, slugify(asset_id))
+                } else {
+                    format!("{} ({})", stem, file_stem(asset_id))
+                };
+                file_names.insert(stem.clone());
+            }
+            let file_name = format!("{}.{}", stem, format.extension());
+            index.push(format::IndexEntry {
+                title: title.clone(),
+                stem,
+                file_name: file_name.clone(),
+                count: book.len(),
+            });
+            write(&dir.join(file_name), book.into_iter().cloned().collect())?;
+        }
+        if args.book_index {
+            let output = format::BookIndex(index, format).to_string();
+            let path = dir.join("index.md");
+            if !write_output(&path, &output, encoding)? {
+                eprintln!("{}: unchanged", path.display());
+            }
+        }
+    } else if let Some(path) = &args.sqlite_out {
+        let progress = progress_bar(args.progress, annotations.len());
+        write_sqlite(path, &annotations, &progress)
+            .context(Errors::UnableToWriteSqlite(path.clone()))?;
+        progress.finish_and_clear();
+    } else if args.tar {
+        let mut archive = tar::Builder::new(vec![]);
+        let mut file_names = HashSet::new();
+        for book in format::group_by_book(&annotations, &format_options) {
+            let mut stem = if format.is_post() {
+                post_stem(&book, format_options.timezone)
+            } else {
+                slugify(&book[0].book_title)
+            };
+            // Different books may have the same title
+            if !file_names.insert(stem.clone()) {
+                stem = format!("{}-{}", stem, slugify(&book[0].asset_id));
+                file_names.insert(stem.clone());
+            }
+            let annotations = book.into_iter().cloned().collect();
+            let content = format!("{}\n", format.render(annotations, &books, &format_options));
+            let content = encoding.encode(&content);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(now.timestamp().max(0) as u64);
+            let file_name = format!("{}.{}", stem, format.extension());
+            archive.append_data(&mut header, file_name, content.as_slice())?;
+        }
+        let archive = archive.into_inner()?;
+        match &args.output {
+            Some(path) => write_atomically(path, &archive)
+                .context(Errors::UnableToWriteOutput(path.clone()))?,
+            None => io::stdout().write_all(&archive)?,
+        }
+    } else if let (true, Some(dir)) = (args.split_by_color, &args.output) {
+        fs::create_dir_all(dir).context(Errors::UnableToWriteOutput(dir.clone()))?;
+        let mut colors = BTreeMap::<_, Vec<_>>::new();
+        for a in annotations {
+            let name = a.color.map(HighlightColor::name).unwrap_or("uncolored");
+            colors.entry(name).or_default().push(a);
+        }
+        for (name, annotations) in colors {
+            let file_name = format!("{}.{}", name, format.extension());
+            write(&dir.join(file_name), annotations)?;
+        }
+    } else if let Some(path) = &args.output {
+        write(path, annotations)?;
+    } else if args.clipboard {
+        let count = annotations.len();
+        copy_to_clipboard(&render(annotations)?)?;
+        eprintln!("Copied {} annotations to clipboard", count);
+    } else {
+        print(annotations)?;
+    }
+
+    if args.update && !args.read_only {
+        if let Some(state) = new_sync_state {
+            if args.confirm && !confirm_update(synced_count, &state)? {
+                return Ok(());
+            }
+            for file in sync_files() {
+                if args.backup_sync {
+                    file.backup()?;
+                }
+                debug!("Updating last sync time of {:?}: {}", file.0, state.time);
+                file.update(&state, args.sync_format)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// File name (without extension) for a book title, characters not allowed in file names are
+/// replaced with `_`
+fn file_stem(title: &str) -> String {
+    let stem = title
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    // Leading dot makes file hidden
+    let stem = stem.trim().trim_start_matches('.');
+    if stem.is_empty() {
+        "_".to_string()
+    } else {
+        stem.to_string()
+    }
+}
+
+/// File name (without extension) of a blog post: date of the latest highlight followed by
+/// slugified book title, eg. `2023-03-08-book-one`
+fn post_stem(book: &[&Annotation], timezone: Timezone) -> String {
+    let latest = book
+        .iter()
+        .map(|a| a.anotation_time)
+        .max()
+        .unwrap_or_default();
+    format!(
+        "{}-{}",
+        timezone.convert(latest).format("%Y-%m-%d"),
+        slugify(&book[0].book_title)
+    )
+}
+
+/// Lowercase ASCII slug with words separated by `-`
+fn slugify(text: &str) -> String {
+    let slug = deunicode::deunicode(text)
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    if slug.is_empty() {
+        "_".to_string()
+    } else {
+        slug
+    }
+}
+
+/// Stable id of an annotation made of `h-` and slug of the UUID
+///
+/// Annotations without UUID use hex of FNV-1a hash of book title and text instead, so the id
+/// is the same on every export.
+fn annotation_slug(uuid: Option<&str>, book_title: &str, text: &str) -> String {
+    if let Some(uuid) = uuid.filter(|u| !u.is_empty()) {
+        return format!("h-{}", slugify(uuid));
+    }
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in book_title.bytes().chain([0]).chain(text.bytes()) {
+        hash = (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3);
+    }
+    format!("h-{:016x}", hash)
+}
+
+/// Files written by `--export-all` and their formats
+const EXPORT_ALL_FORMATS: [(&str, OutputFormat); 4] = [
+    ("highlights.json", OutputFormat::Json),
+    ("highlights.md", OutputFormat::Logseq),
+    ("highlights.csv", OutputFormat::Csv),
+    ("highlights.html", OutputFormat::Html),
+];
+
+/// Asks on stderr whether the sync-file should be updated
+///
+/// Without a terminal there is nobody to answer, so sync-file is not updated.
+fn confirm_update(synced_count: usize, state: &SyncState) -> Result<bool> {
+    eprintln!(
+        "{} annotations synced, new sync time is {}",
+        synced_count,
+        local_time(state.time)
+    );
+    if !io::stdin().is_terminal() {
+        eprintln!("Sync-file is not updated: --confirm requires a terminal");
+        return Ok(false);
+    }
+    eprint!("Update sync-file? [y/N] ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let confirmed = matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+    if !confirmed {
+        eprintln!("Sync-file is not updated");
+    }
+    Ok(confirmed)
+}
+
+/// Prints every output format with description and sample output
+fn print_format_help() {
+    let sample = || {
+        let time = Utc.with_ymd_and_hms(2022, 12, 24, 3, 56, 40).unwrap();
+        vec![
+            Annotation {
+                selected_text: "annotation 1".into(),
+                note: None,
+                anotation_time: time,
+                created_at: time,
+                modified_at: None,
+                book_title: "Book 1".into(),
+                author: None,
+                asset_id: "A1B2C3".into(),
+                location: None,
+                location_start: None,
+                location_end: None,
+                color: Some(HighlightColor::Yellow),
+                annotation_type: "highlight".into(),
+                uuid: None,
+                context: None,
+                word_count: 2,
+                favorited: None,
+                slug: "h-1".into(),
+                raw_length: None,
+                number: None,
+            },
+            Annotation {
+                selected_text: "annotation 2".into(),
+                note: Some("note".into()),
+                anotation_time: time,
+                created_at: time,
+                modified_at: None,
+                book_title: "Book 1".into(),
+                author: None,
+                asset_id: "A1B2C3".into(),
+                location: None,
+                location_start: None,
+                location_end: None,
+                color: Some(HighlightColor::Yellow),
+                annotation_type: "note".into(),
+                uuid: None,
+                context: None,
+                word_count: 2,
+                favorited: None,
+                slug: "h-2".into(),
+                raw_length: None,
+                number: None,
+            },
+        ]
+    };
+
+    for format in OutputFormat::value_variants() {
+        if let Some(value) = format.to_possible_value() {
+            println!(
+                "{} - {}",
+                value.get_name(),
+                value.get_help().unwrap_or_default()
+            );
+        }
+        println!();
+        let books = HashMap::new();
+        let output = format.render(sample(), &books, &format::Options::default());
+        for line in output.lines() {
+            println!("    {}", line);
+        }
+        println!();
+    }
+}
+
+/// Returns the time all "now"-relative computations should use.
+///
+/// Explicit `--now` has priority, then `SOURCE_DATE_EPOCH` environment variable
+/// (seconds since Unix epoch), and finally the current system time.
+fn reference_time(now: Option<DateTime<Utc>>) -> Result<DateTime<Utc>> {
+    if let Some(now) = now {
+        return Ok(now);
+    }
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => {
+            let ts = value
+                .trim()
+                .parse::<i64>()
+                .map_err(|_| Errors::InvalidSourceDateEpoch(value.clone()))?;
+            Ok(Utc
+                .timestamp_opt(ts, 0)
+                .single()
+                .ok_or(Errors::InvalidSourceDateEpoch(value))?)
+        }
+        Err(_) => Ok(Utc::now()),
+    }
+}
+
+fn parse_profile(value: &str) -> Result<String> {
+    let valid = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if value.is_empty() || !valid {
+        anyhow::bail!("profile name may contain only letters, digits, '-' and '_'");
+    }
+    Ok(value.to_string())
+}
+
+/// Replaces `\n`, `\t` and `\\` escape sequences, other backslashes are kept as is
+fn parse_escapes(value: &str) -> Result<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('\\') => result.push('\\'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+    Ok(result)
+}
+
+fn parse_locale(value: &str) -> Result<String> {
+    format::Locale::new(value)?;
+    Ok(value.to_string())
+}
+
+/// Parses UTC offset like `+03:00`, `-0530` or `Z`
+fn parse_offset(value: &str) -> Result<FixedOffset> {
+    if value.eq_ignore_ascii_case("z") || value.eq_ignore_ascii_case("utc") {
+        return Ok(FixedOffset::east_opt(0).expect("Invalid offset"));
+    }
+    let (sign, rest) = match value.split_at_checked(1) {
+        Some(("+", rest)) => (1, rest),
+        Some(("-", rest)) => (-1, rest),
+        _ => anyhow::bail!("expected offset like +03:00"),
+    };
+    let (hours, minutes) = rest
+        .split_once(':')
+        .unwrap_or_else(|| rest.split_at(rest.len().min(2)));
+    let hours: i32 = hours.parse().context("expected offset like +03:00")?;
+    let minutes: i32 = match minutes {
+        "" => 0,
+        m => m.parse().context("expected offset like +03:00")?,
+    };
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).context("offset is out of range")
+}
+
+fn parse_color_label(value: &str) -> Result<(HighlightColor, String)> {
+    let (color, label) = value.split_once('=').context("expected COLOR=MEANING")?;
+    let color = HighlightColor::from_str(color.trim(), true).map_err(anyhow::Error::msg)?;
+    Ok((color, label.trim().to_string()))
+}
+
+fn parse_glob(value: &str) -> Result<Pattern> {
+    Ok(Pattern::new(value)?)
+}
+
+fn parse_rfc3339(value: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc))
+}
+
+/// Parses RFC3339 time or a date (beginning of the day in local timezone)
+fn parse_date(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0).expect("Invalid time");
+        let local = Local
+            .from_local_datetime(&midnight)
+            .earliest()
+            .context("Date doesn't exist in local timezone")?;
+        return Ok(local.with_timezone(&Utc));
+    }
+    parse_rfc3339(value)
+}
+
+/// Book metadata from library database
+#[derive(Serialize, Clone)]
+struct Book {
+    /// Time the book was added to the library
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_added: Option<DateTime<Utc>>,
+    /// Time the book was last opened
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_opened: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    year: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    publisher: Option<String>,
+    /// Whether the book is marked as finished (or read to the end)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished: Option<bool>,
+    /// Cover image of the book, only if the file exists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cover: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    genre: Option<String>,
+    /// Reading progress from 0 to 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<f64>,
+}
+
+/// Book of the library with its metadata, see [`read_library`]
+#[derive(Serialize)]
+struct LibraryEntry {
+    asset_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+    #[serde(flatten)]
+    book: Option<Book>,
+}
+
+/// Reads all books of the library ordered by title, with or without annotations
+fn read_library(library_db: impl AsRef<Path>) -> Result<Vec<LibraryEntry>> {
+    let mut books = read_books(&library_db)?;
+    let connection = open_read_only(library_db)?;
+    let mut stmt = connection.prepare(
+        "select ZASSETID, ZTITLE, ZAUTHOR
+         from ZBKLIBRARYASSET
+         where ZASSETID IS NOT NULL
+         ORDER BY ZTITLE",
+    )?;
+    let text = |value: Option<String>| value.filter(|v| !v.trim().is_empty());
+    let entries = stmt.query_map([], |row| {
+        let asset_id: String = row.get(0)?;
+        Ok(LibraryEntry {
+            book: books.remove(&asset_id),
+            asset_id,
+            title: text(row.get(1)?),
+            author: text(row.get(2)?),
+        })
+    })?;
+    entries
+        .map(|r| r.map_err(schema_error))
+        .collect::<Result<Vec<_>>>()
+}
+
+/// Reads metadata of all books in the library indexed by asset id
+fn read_books(library_db: impl AsRef<Path>) -> Result<HashMap<String, Book>> {
+    let connection = open_read_only(library_db)?;
+    // Not every iBooks version stores year, publisher, reading status, cover and genre
+    let columns = table_columns(&connection, "main", "ZBKLIBRARYASSET")?;
+    let column = |column: &str| match columns.iter().any(|c| c == column) {
+        true => column.to_string(),
+        false => "NULL".to_string(),
+    };
+    let mut stmt = connection.prepare(&format!(
+        "select ZASSETID, ZCREATIONDATE, ZLASTOPENDATE, ZLANGUAGE,
+            CAST({} AS TEXT), CAST({} AS TEXT), coalesce({} != 0, {} >= 1.0), {}, {}, {}
+         from ZBKLIBRARYASSET
+         where ZASSETID IS NOT NULL",
+        column("ZYEAR"),
+        column("ZPUBLISHER"),
+        column("ZISFINISHED"),
+        column("ZREADINGPROGRESS"),
+        column("ZCOVERURL"),
+        column("ZGENRE"),
+        column("ZREADINGPROGRESS")
+    ))?;
+    let text = |value: Option<String>| value.filter(|v| !v.trim().is_empty());
+    let date = |ts: Option<f64>| ts.and_then(core_data_to_timestamp);
+    let books = stmt.query_map([], |row| {
+        let book = Book {
+            date_added: date(row.get(1)?),
+            last_opened: date(row.get(2)?),
+            language: row.get(3)?,
+            year: text(row.get(4)?),
+            publisher: text(row.get(5)?),
+            finished: row.get(6)?,
+            cover: row
+                .get::<_, Option<String>>(7)?
+                .as_deref()
+                .and_then(cover_path),
+            genre: text(row.get(8)?),
+            progress: row.get(9)?,
+        };
+        Ok((row.get(0)?, book))
+    })?;
+    books
+        .map(|r| r.map_err(schema_error))
+        .collect::<Result<HashMap<_, _>>>()
+}
+
+/// Path of a cover image for a `ZCOVERURL` value (`file://` URL or a plain path)
+///
+/// Returns `None` for remote URLs and files which don't exist.
+fn cover_path(url: &str) -> Option<PathBuf> {
+    let path = match url.strip_prefix("file://") {
+        Some(path) => PathBuf::from(percent_decode(path)?),
+        None if url.contains("://") => return None,
+        None => PathBuf::from(url),
+    };
+    path.is_file().then_some(path)
+}
+
+/// Decodes `%XX` escapes of an URL path, fails on malformed escapes and invalid UTF-8
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        if b == b'%' {
+            let hex = [input.next()?, input.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Asset ids and titles of all the books in the library
+fn read_book_titles(library_db: impl AsRef<Path>) -> Result<Vec<(String, String)>> {
+    let connection = open_read_only(library_db)?;
+    let mut stmt = connection.prepare(
+        "select ZASSETID, ZTITLE
+         from ZBKLIBRARYASSET
+         where ZASSETID IS NOT NULL AND ZTITLE IS NOT NULL",
+    )?;
+    let books = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    books
+        .map(|r| r.map_err(schema_error))
+        .collect::<Result<Vec<_>>>()
+}
+
+/// Book (asset id and title) which title matches the query best
+///
+/// Fails if nothing matches or if several books match almost equally well, the latter error
+/// lists the candidates.
+fn fuzzy_find_book(books: &[(String, String)], query: &str) -> Result<(String, String)> {
+    let matcher = SkimMatcherV2::default().ignore_case();
+    let mut matches = books
+        .iter()
+        .filter_map(|(id, title)| Some((matcher.fuzzy_match(title, query)?, id, title)))
+        .collect::<Vec<_>>();
+    matches.sort_by_key(|(score, _, title)| (Reverse(*score), title.as_str()));
+    let Some(&(best, id, title)) = matches.first() else {
+        return Err(Errors::NoBookMatched(query.to_string()).into());
+    };
+    // Scores within 10% of the best one are considered ambiguous
+    let close = matches
+        .iter()
+        .filter(|(score, _, t)| *score * 10 >= best * 9 && *t != title)
+        .map(|(_, _, t)| t.as_str());
+    let close = close.collect::<Vec<_>>();
+    if !close.is_empty() {
+        let candidates = [title.as_str()]
+            .into_iter()
+            .chain(close)
+            .collect::<Vec<_>>();
+        return Err(Errors::AmbiguousBook(query.to_string(), candidates.join("\n  ")).into());
+    }
+    Ok((id.clone(), title.clone()))
+}
+
+/// SQL expression for annotation creation time
+///
+/// Here I'm using ZFUTUREPROOFING6 instead of ZANNOTATIONMODIFICATIONDATE beacuse
+/// latter works unreliably. It seems like ZFUTUREPROOFING6 is annotation created time.
+/// At least I've found one other project using it:
+/// https://github.com/jay1803/ibook-server/blob/58838a3a1004aeaaa7cd7ebf4ef95edf8cc45ed3/controllers/bookController.js#L124
+///
+/// Some rows have no ZFUTUREPROOFING6, then creation or modification date is used instead.
+const CREATION_TIME: &str =
+    "coalesce(a.ZFUTUREPROOFING6, a.ZANNOTATIONCREATIONDATE, a.ZANNOTATIONMODIFICATIONDATE, 0)";
+
+/// Characters trimmed by SQL `trim()` to check for blank text
+const SQL_WHITESPACE: &str = "' ' || char(9) || char(10) || char(13)";
+
+/// Database level filters applied when reading annotations
+#[derive(Default)]
+struct AnnotationQuery {
+    /// Only annotations created after given time
+    created_after: Option<DateTime<Utc>>,
+    /// Only annotations modified after given time
+    modified_after: Option<DateTime<Utc>>,
+    /// Only annotations created or modified after given time
+    active_after: Option<DateTime<Utc>>,
+    /// Include annotations marked as deleted
+    include_deleted: bool,
+    /// Only annotations of books with given asset ids (all books if empty)
+    book_ids: Vec<String>,
+    /// Print bound SQL to stderr before reading
+    explain: bool,
+    /// Interrupt reading after given number of seconds
+    timeout: Option<u64>,
+    /// Print time spent opening databases and querying to stderr
+    timings: bool,
+    /// Fail if any annotation is skipped or read with a fallback
+    strict: bool,
+    /// Skip rows failed to read instead of failing
+    skip_errors: bool,
+}
+
+fn read_annotations(
+    annotation_db: impl AsRef<Path>,
+    secondary_dbs: &[PathBuf],
+    library_db: impl AsRef<Path>,
+    query: &AnnotationQuery,
+) -> Result<Vec<Annotation>> {
+    let (connection, schemas) =
+        open_databases(annotation_db, secondary_dbs, library_db, query.timings)?;
+    let _timeout = query.timeout.map(|secs| interrupt_after(&connection, secs));
+    let annotation_table = annotation_table(&connection, &schemas)?;
+    let (mut conditions, content_conditions, params) = query_conditions(query);
+
+    let mut problems = vec![];
+    if query.strict {
+        problems = skipped_annotations(
+            &connection,
+            &annotation_table,
+            &conditions.join(" AND "),
+            &content_conditions.join(" AND "),
+            &params,
+        )?;
+    }
+    conditions.extend(content_conditions);
+
+    let started = Instant::now();
+    let mut stms = connection.prepare(&format!(
+        "select
+            a.ZANNOTATIONSELECTEDTEXT,
+            a.ZANNOTATIONNOTE,
+            a.ZFUTUREPROOFING6,
+            l.ZTITLE,
+            a.ZANNOTATIONASSETID,
+            a.ZANNOTATIONLOCATION,
+            a.ZANNOTATIONSTYLE,
+            a.ZANNOTATIONUUID,
+            a.ZANNOTATIONCREATIONDATE,
+            a.ZANNOTATIONMODIFICATIONDATE,
+            a.ZANNOTATIONREPRESENTATIVETEXT,
+            l.ZAUTHOR,
+            a.ZANNOTATIONISFAVORITE
+         from ({}) a
+         inner join ZBKLIBRARYASSET l ON l.ZASSETID = a.ZANNOTATIONASSETID
+         where {}
+         ORDER BY {}",
+        annotation_table,
+        conditions.join(" AND "),
+        CREATION_TIME
+    ))?;
+    let mut fallbacks = 0;
+    let mut lossy_titles = HashSet::new();
+    let rows = stms.query(params_from_iter(params))?;
+    if query.explain {
+        if let Some(sql) = rows.as_ref().and_then(Statement::expanded_sql) {
+            eprintln!("{}", sql);
+        }
+    }
+    let annotations = rows.mapped(|row| {
+        let ts = match row.get::<_, Option<f64>>(2)? {
+            Some(ts) => ts,
+            None => {
+                fallbacks += 1;
+                if query.strict {
+                    let uuid: Option<String> = row.get(7)?;
+                    problems.push((
+                        annotation_id(uuid.as_deref(), &row.get::<_, String>(4)?),
+                        "no ZFUTUREPROOFING6 timestamp".to_string(),
+                    ));
+                }
+                let created: Option<f64> = row.get(8)?;
+                let modified: Option<f64> = row.get(9)?;
+                created.or(modified).unwrap_or_default()
+            }
+        };
+        let selected_text: String = row.get(0)?;
+        let note: Option<String> = row.get(1)?;
+        let style: Option<i64> = row.get(6)?;
+        let asset_id: String = row.get(4)?;
+        let location: Option<String> = row.get(5)?;
+        // Title is read as bytes, so single oddly-encoded title doesn't break the whole export
+        let book_title = match row.get_ref(3)?.as_bytes_or_null()? {
+            Some(bytes) => match String::from_utf8_lossy(bytes) {
+                Cow::Borrowed(title) => title.to_string(),
+                Cow::Owned(title) => {
+                    if query.strict {
+                        let uuid: Option<String> = row.get(7)?;
+                        problems.push((
+                            annotation_id(uuid.as_deref(), &asset_id),
+                            "book title is not valid UTF-8".to_string(),
+                        ));
+                    }
+                    lossy_titles.insert(asset_id.clone());
+                    title
+                }
+            },
+            None => String::new(),
+        };
+        let uuid: Option<String> = row.get(7)?;
+        let Some(time) = core_data_to_timestamp(ts) else {
+            let id = annotation_id(uuid.as_deref(), &asset_id);
+            if query.strict {
+                problems.push((id, format!("invalid timestamp {}", ts)));
+            } else {
+                warn!("Skipping annotation {} with invalid timestamp {}", id, ts);
+            }
+            return Ok(None);
+        };
+        Ok(Some(Annotation {
+            slug: annotation_slug(uuid.as_deref(), &book_title, &selected_text),
+            raw_length: raw_text_length(row.get_ref(0)?),
+            number: None,
+            word_count: word_count(&selected_text),
+            annotation_type: annotation_type(style, note.as_deref()).to_string(),
+            selected_text,
+            note,
+            anotation_time: time,
+            created_at: time,
+            modified_at: row
+                .get::<_, Option<f64>>(9)?
+                .and_then(core_data_to_timestamp),
+            book_title,
+            author: row.get::<_, Option<String>>(11)?.filter(|a| !a.is_empty()),
+            asset_id,
+            location_start: location.as_deref().and_then(cfi_range).map(|(s, _)| s),
+            location_end: location.as_deref().and_then(cfi_range).map(|(_, e)| e),
+            location,
+            color: style.and_then(HighlightColor::from_style),
+            uuid,
+            context: row
+                .get::<_, Option<String>>(10)?
+                .filter(|c| !c.trim().is_empty()),
+            favorited: row.get(12)?,
+        }))
+    });
+
+    let annotations = collect_annotations(annotations, query.skip_errors)?;
+    report_timing(query.timings, "query", started);
+    check_strict(&problems)?;
+    if fallbacks > 0 {
+        warn!(
+            "{} annotations have no ZFUTUREPROOFING6, used creation/modification date instead",
+            fallbacks
+        );
+    }
+    for asset_id in lossy_titles {
+        warn!(
+            "Title of book {} is not valid UTF-8, invalid characters replaced",
+            asset_id
+        );
+    }
+    Ok(annotations)
+}
+
+/// SQL conditions (and their parameters) on annotations of the query
+///
+/// Filters of the query are returned separately from conditions on annotation content, so
+/// `--strict` can find annotations skipped because of their content.
+fn query_conditions(query: &AnnotationQuery) -> (Vec<String>, [String; 3], Vec<Value>) {
+    let created_after = query
+        .created_after
+        .map(timestamp_to_core_data)
+        .unwrap_or(f64::MIN);
+    let content_conditions = [
+        "a.ZANNOTATIONSELECTEDTEXT IS NOT NULL".to_string(),
+        "(a.ZANNOTATIONNOTE != '' OR a.ZANNOTATIONNOTE IS NULL)".to_string(),
+        // Highlights without text are skipped like NULL ones, unless there is a note to export
+        format!(
+            "(trim(a.ZANNOTATIONSELECTEDTEXT, {}) != '' OR a.ZANNOTATIONNOTE IS NOT NULL)",
+            SQL_WHITESPACE
+        ),
+    ];
+    let mut conditions = vec![format!("{} > ?", CREATION_TIME)];
+    let mut params = vec![Value::from(created_after)];
+
+    if let Some(modified_after) = query.modified_after {
+        conditions.push("a.ZANNOTATIONMODIFICATIONDATE > ?".to_string());
+        params.push(Value::from(timestamp_to_core_data(modified_after)));
+    }
+    if let Some(active_after) = query.active_after {
+        conditions.push(format!(
+            "({} > ? OR a.ZANNOTATIONMODIFICATIONDATE > ?)",
+            CREATION_TIME
+        ));
+        let active_after = timestamp_to_core_data(active_after);
+        params.extend([Value::from(active_after), Value::from(active_after)]);
+    }
+    // iBooks doesn't remove deleted annotations right away, but marks them with ZANNOTATIONDELETED flag
+    if !query.include_deleted {
+        conditions.push("(a.ZANNOTATIONDELETED = 0 OR a.ZANNOTATIONDELETED IS NULL)".to_string());
+    }
+    if !query.book_ids.is_empty() {
+        let placeholders = vec!["?"; query.book_ids.len()].join(", ");
+        conditions.push(format!("a.ZANNOTATIONASSETID IN ({})", placeholders));
+        params.extend(query.book_ids.iter().cloned().map(Value::from));
+    }
+    (conditions, content_conditions, params)
+}
+
+/// Number of annotations matching database level filters of the query, nothing is read
+///
+/// Limit and offset of the query are not applied.
+fn estimate_annotations(
+    annotation_db: impl AsRef<Path>,
+    secondary_dbs: &[PathBuf],
+    library_db: impl AsRef<Path>,
+    query: &AnnotationQuery,
+) -> Result<i64> {
+    let (connection, schemas) =
+        open_databases(annotation_db, secondary_dbs, library_db, query.timings)?;
+    let _timeout = query.timeout.map(|secs| interrupt_after(&connection, secs));
+    let (mut conditions, content_conditions, params) = query_conditions(query);
+    conditions.extend(content_conditions);
+    let started = Instant::now();
+    let count = connection.query_row(
+        &format!(
+            "select count(*)
+             from ({}) a
+             inner join ZBKLIBRARYASSET l ON l.ZASSETID = a.ZANNOTATIONASSETID
+             where {}",
+            annotation_table(&connection, &schemas)?,
+            conditions.join(" AND ")
+        ),
+        params_from_iter(params),
+        |row| row.get(0),
+    )?;
+    report_timing(query.timings, "query", started);
+    Ok(count)
+}
+
+/// Annotations of the query which book is missing in the library database
+///
+/// Export skips them (annotations are joined with the library), so they are read separately
+/// to troubleshoot missing highlights. Content conditions of the query are not applied.
+fn read_orphans(
+    annotation_db: impl AsRef<Path>,
+    secondary_dbs: &[PathBuf],
+    library_db: impl AsRef<Path>,
+    query: &AnnotationQuery,
+) -> Result<Vec<Orphan>> {
+    let (connection, schemas) =
+        open_databases(annotation_db, secondary_dbs, library_db, query.timings)?;
+    let _timeout = query.timeout.map(|secs| interrupt_after(&connection, secs));
+    let (conditions, _, params) = query_conditions(query);
+    let mut stmt = connection.prepare(&format!(
+        "select a.ZANNOTATIONUUID, a.ZANNOTATIONASSETID, a.ZANNOTATIONSELECTEDTEXT
+         from ({}) a
+         left join l.ZBKLIBRARYASSET l ON l.ZASSETID = a.ZANNOTATIONASSETID
+         where l.ZASSETID IS NULL AND {}
+         ORDER BY {}",
+        annotation_table(&connection, &schemas)?,
+        conditions.join(" AND "),
+        CREATION_TIME
+    ))?;
+    let orphans = stmt
+        .query_map(params_from_iter(params), |row| {
+            Ok(Orphan {
+                uuid: row.get(0)?,
+                asset_id: row.get(1)?,
+                selected_text: row.get(2)?,
+            })
+        })?
+        .map(|r| r.map_err(schema_error))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(orphans)
+}
+
+/// Annotations passing `filters` but skipped by `content` conditions or missing in the library
+///
+/// Returns annotation id and the reason it is skipped.
+fn skipped_annotations(
+    connection: &Connection,
+    annotation_table: &str,
+    filters: &str,
+    content: &str,
+    params: &[Value],
+) -> Result<Vec<(String, String)>> {
+    let mut stmt = connection.prepare(&format!(
+        "select
+            a.ZANNOTATIONUUID,
+            a.ZANNOTATIONASSETID,
+            case
+                when l.ZASSETID IS NULL then 'book is not found in the library'
+                when a.ZANNOTATIONSELECTEDTEXT IS NULL then 'selected text is NULL'
+                when a.ZANNOTATIONNOTE = '' then 'note is empty'
+                else 'selected text is blank'
+            end
+         from ({}) a
+         left join l.ZBKLIBRARYASSET l ON l.ZASSETID = a.ZANNOTATIONASSETID
+         where {} AND (l.ZASSETID IS NULL OR NOT ({}))",
+        annotation_table, filters, content
+    ))?;
+    let skipped = stmt
+        .query_map(params_from_iter(params), |row| {
+            let uuid: Option<String> = row.get(0)?;
+            let asset_id: Option<String> = row.get(1)?;
+            Ok((
+                annotation_id(uuid.as_deref(), asset_id.as_deref().unwrap_or_default()),
+                row.get(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(skipped)
+}
+
+/// Human readable id of an annotation for diagnostic messages
+fn annotation_id(uuid: Option<&str>, asset_id: &str) -> String {
+    format!("{} of {}", uuid.unwrap_or("without uuid"), asset_id)
+}
+
+/// Fails with [`Errors::StrictViolations`] if there are any `--strict` problems, printing
+/// every one of them to stderr
+fn check_strict(problems: &[(String, String)]) -> Result<()> {
+    for (id, reason) in problems {
+        eprintln!("Annotation {}: {}", id, reason);
+    }
+    match problems.len() {
+        0 => Ok(()),
+        n => Err(Errors::StrictViolations(n).into()),
+    }
+}
+
+/// Length of a text column value in characters (invalid UTF-8 sequences count as one character)
+fn raw_text_length(value: ValueRef) -> Option<usize> {
+    let bytes = value.as_bytes().ok()?;
+    Some(String::from_utf8_lossy(bytes).chars().count())
+}
+
+/// Collects annotations read from rows, rows mapped to `None` (eg. with invalid timestamp) are
+/// left out
+///
+/// With `skip_errors` rows which values can't be converted are logged and skipped, other errors
+/// (eg. interrupted query) fail anyway.
+fn collect_annotations(
+    rows: impl Iterator<Item = rusqlite::Result<Option<Annotation>>>,
+    skip_errors: bool,
+) -> Result<Vec<Annotation>> {
+    let mut annotations = vec![];
+    let mut skipped = 0;
+    for (idx, row) in rows.enumerate() {
+        match row {
+            Ok(a) => annotations.extend(a),
+            Err(
+                e @ (rusqlite::Error::InvalidColumnType(..)
+                | rusqlite::Error::FromSqlConversionFailure(..)
+                | rusqlite::Error::IntegralValueOutOfRange(..)
+                | rusqlite::Error::Utf8Error(_)),
+            ) if skip_errors => {
+                skipped += 1;
+                warn!("Skipping row {}: {:#}", idx + 1, schema_error(e));
+            }
+            Err(e) => return Err(schema_error(e).context(Errors::ContextProcessingAnnotation)),
+        }
+    }
+    if skipped > 0 {
+        eprintln!(
+            "{} annotations skipped because of errors (--skip-errors), run with \
+            RUST_LOG=warn to see them",
+            skipped
+        );
+    }
+    Ok(annotations)
+}
+
+/// Prints time elapsed since `started` to stderr, if `--profile-timings` is given
+fn report_timing(enabled: bool, phase: &str, started: Instant) {
+    if enabled {
+        eprintln!("{}: {:.2?}", phase, started.elapsed());
+    }
+}
+
+/// Reads annotations with user supplied `--sql-file` query
+///
+/// Schema is not checked, the query is expected to return selected text, note, Core Data
+/// timestamp and book title. Only `created_after` of the query is used.
+fn read_annotations_with_sql(
+    annotation_db: impl AsRef<Path>,
+    library_db: impl AsRef<Path>,
+    sql: &str,
+    query: &AnnotationQuery,
+) -> Result<Vec<Annotation>> {
+    let started = Instant::now();
+    let connection = open_read_only(annotation_db)?;
+    report_timing(query.timings, "open database", started);
+    let started = Instant::now();
+    connection.execute("ATTACH DATABASE ? AS l", [library_db.as_ref().to_str()])?;
+    report_timing(query.timings, "attach databases", started);
+    let started = Instant::now();
+    let _timeout = query.timeout.map(|secs| interrupt_after(&connection, secs));
+    let mut stmt = connection.prepare(sql)?;
+    if stmt.column_count() != 4 {
+        return Err(Errors::SqlFileColumns(stmt.column_count()).into());
+    }
+    let created_after = query
+        .created_after
+        .map(timestamp_to_core_data)
+        .unwrap_or(f64::MIN);
+    let params = match stmt.parameter_count() {
+        0 => vec![],
+        1 => vec![Value::from(created_after)],
+        n => return Err(Errors::SqlFileParameters(n).into()),
+    };
+    let rows = stmt.query(params_from_iter(params))?;
+    if query.explain {
+        if let Some(sql) = rows.as_ref().and_then(Statement::expanded_sql) {
+            eprintln!("{}", sql);
+        }
+    }
+    let annotations = rows.mapped(|row| {
+        let selected_text: String = row.get::<_, Option<String>>(0)?.unwrap_or_default();
+        let ts = row.get::<_, Option<f64>>(2)?.unwrap_or_default();
+        let Some(time) = core_data_to_timestamp(ts) else {
+            warn!("Skipping annotation with invalid timestamp {}", ts);
+            return Ok(None);
+        };
+        let note = row.get::<_, Option<String>>(1)?.filter(|n| !n.is_empty());
+        let book_title = row.get::<_, Option<String>>(3)?.unwrap_or_default();
+        let slug = annotation_slug(None, &book_title, &selected_text);
+        let raw_length = raw_text_length(row.get_ref(0)?);
+        Ok(Some(Annotation {
+            word_count: word_count(&selected_text),
+            // Style isn't returned by the query
+            annotation_type: annotation_type(None, note.as_deref()).to_string(),
+            selected_text,
+            note,
+            anotation_time: time,
+            created_at: time,
+            modified_at: None,
+            author: None,
+            asset_id: String::new(),
+            location: None,
+            location_start: None,
+            location_end: None,
+            color: None,
+            uuid: None,
+            context: None,
+            favorited: None,
+            raw_length,
+            number: None,
+            slug,
+            book_title,
+        }))
+    });
+    let annotations = collect_annotations(annotations, query.skip_errors)?;
+    report_timing(query.timings, "query", started);
+    Ok(annotations)
+}
+
+/// Opens annotation database read-only with library database attached as `l`
+///
+/// Secondary annotation databases are attached as `s1`, `s2` and so on. Returns connection
+/// and the names of attached schemas (with their table layouts) which annotations should be
+/// read from. Secondary databases without annotation table are detached and skipped. Fails if
+/// any of the required columns is missing.
+fn open_databases(
+    annotation_db: impl AsRef<Path>,
+    secondary_dbs: &[PathBuf],
+    library_db: impl AsRef<Path>,
+    timings: bool,
+) -> Result<(Connection, Vec<(String, AnnotationLayout)>)> {
+    let started = Instant::now();
+    let connection = open_read_only(annotation_db)?;
+    report_timing(timings, "open database", started);
+    let started = Instant::now();
+    connection.execute("ATTACH DATABASE ? AS l", [library_db.as_ref().to_str()])?;
+    let layout = check_schema(&connection)?;
+
+    let mut schemas = vec![("main".to_string(), layout)];
+    for (idx, db) in secondary_dbs.iter().enumerate() {
+        let schema = format!("s{}", idx + 1);
+        connection.execute(&format!("ATTACH DATABASE ? AS {}", schema), [db.to_str()])?;
+        let Some(layout) = AnnotationLayout::detect(&connection, &schema)? else {
+            debug!("No annotations in {:?}, skipping", db);
+            connection.execute(&format!("DETACH DATABASE {}", schema), [])?;
+            continue;
+        };
+        check_annotation_columns(&connection, &schema, layout)?;
+        debug!(
+            "Attached secondary annotation database {:?} as {}",
+            db, schema
+        );
+        schemas.push((schema, layout));
+    }
+    check_databases_match(&connection, &schemas)?;
+    report_timing(timings, "attach databases", started);
+    Ok((connection, schemas))
+}
+
+/// Annotations of all the databases as a single table (SQL subquery)
+///
+/// Columns of every layout are renamed to [`ANNOTATION_COLUMNS`]. Missing
+/// [`OPTIONAL_ANNOTATION_COLUMNS`] are selected as NULL.
+fn annotation_table(
+    connection: &Connection,
+    schemas: &[(String, AnnotationLayout)],
+) -> Result<String> {
+    let mut tables = vec![];
+    for (schema, layout) in schemas {
+        let present = table_columns(connection, schema, layout.table())?;
+        let mut columns = ANNOTATION_COLUMNS
+            .iter()
+            .map(|legacy| {
+                let column = layout.column(legacy);
+                if column == *legacy {
+                    column
+                } else {
+                    format!("{} AS {}", column, legacy)
+                }
+            })
+            .collect::<Vec<_>>();
+        for legacy in OPTIONAL_ANNOTATION_COLUMNS {
+            let column = layout.column(legacy);
+            columns.push(match present.contains(&column) {
+                true if column == *legacy => column,
+                true => format!("{} AS {}", column, legacy),
+                false => format!("NULL AS {}", legacy),
+            });
+        }
+        tables.push(format!(
+            "select {} from {}.{}",
+            columns.join(", "),
+            schema,
+            layout.table()
+        ));
+    }
+    Ok(tables.join(" UNION ALL "))
+}
+
+/// Warns if almost none of the annotated books are present in the library database
+///
+/// Annotations of books missing in the library are not exported, so databases from different
+/// iBooks installs (or snapshots) silently produce empty export.
+fn check_databases_match(
+    connection: &Connection,
+    schemas: &[(String, AnnotationLayout)],
+) -> Result<()> {
+    let (annotated, resolved): (i64, i64) = connection.query_row(
+        &format!(
+            "select count(distinct a.ZANNOTATIONASSETID), count(distinct l.ZASSETID)
+             from ({}) a
+             left join l.ZBKLIBRARYASSET l ON l.ZASSETID = a.ZANNOTATIONASSETID",
+            annotation_table(connection, schemas)?
+        ),
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    debug!(
+        "{} of {} annotated books found in the library",
+        resolved, annotated
+    );
+    if annotated >= 3 && resolved * 10 < annotated {
+        eprintln!(
+            "Warning: only {} of {} annotated books are found in the library database. \
+            Annotation and library databases are probably from different iBooks installs",
+            resolved, annotated
+        );
+    }
+    Ok(())
+}
+
+/// Interrupts queries running on the connection after given number of seconds
+///
+/// Interrupted queries fail with `SQLITE_INTERRUPT`. Interruption is cancelled when the returned
+/// sender is dropped.
+fn interrupt_after(connection: &Connection, secs: u64) -> mpsc::Sender<()> {
+    let handle = connection.get_interrupt_handle();
+    let (tx, rx) = mpsc::channel::<()>();
+    std::thread::spawn(move || {
+        let timeout = std::time::Duration::from_secs(secs);
+        if let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(timeout) {
+            handle.interrupt();
+        }
+    });
+    tx
+}
+
+/// Checks if error is caused by a query interrupted with [`interrupt_after`]
+fn is_interrupted(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        matches!(
+            e.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::SqliteFailure(err, _))
+                if err.code == rusqlite::ErrorCode::OperationInterrupted
+        )
+    })
+}
+
+fn open_read_only(db: impl AsRef<Path>) -> Result<Connection> {
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    Ok(Connection::open_with_flags(db, flags)?)
+}
+
+/// Checks that all the columns required for export are present in both databases
+///
+/// Expects library database to be attached as `l`. Returns layout of the annotation table.
+fn check_schema(connection: &Connection) -> Result<AnnotationLayout> {
+    // Missing table is reported as missing columns of the legacy one
+    let layout = AnnotationLayout::detect(connection, "main")?.unwrap_or(AnnotationLayout::Legacy);
+    check_annotation_columns(connection, "main", layout)?;
+    check_columns(connection, "l", "ZBKLIBRARYASSET", LIBRARY_COLUMNS)?;
+    Ok(layout)
+}
+
+fn check_annotation_columns(
+    connection: &Connection,
+    schema: &str,
+    layout: AnnotationLayout,
+) -> Result<()> {
+    let columns = layout.columns();
+    let columns = columns.iter().map(String::as_str).collect::<Vec<_>>();
+    check_columns(connection, schema, layout.table(), &columns)
+}
+
+fn check_columns(
+    connection: &Connection,
+    schema: &str,
+    table: &str,
+    required: &[&str],
+) -> Result<()> {
+    let columns = table_columns(connection, schema, table)?;
+    if let Some(column) = required.iter().find(|c| !columns.iter().any(|n| n == *c)) {
+        return Err(Errors::SchemaColumnMissing {
+            table: table.to_string(),
+            column: column.to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Column names of a table, empty if table doesn't exist
+fn table_columns(connection: &Connection, schema: &str, table: &str) -> Result<Vec<String>> {
+    let mut stmt = connection.prepare(&format!("PRAGMA {}.table_info({})", schema, table))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>("name"))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(columns)
+}
+
+/// Replaces type mismatch errors with more descriptive [`Errors::UnexpectedColumnType`]
+fn schema_error(e: rusqlite::Error) -> anyhow::Error {
+    match e {
+        rusqlite::Error::InvalidColumnType(_, column, column_type) => {
+            Errors::UnexpectedColumnType {
+                column,
+                column_type: column_type.to_string(),
+            }
+            .into()
+        }
+        e => e.into(),
+    }
+}
+
+/// Kind of annotation for a `ZANNOTATIONSTYLE` value and a note
+///
+/// Annotations with a note are `note`, regardless of the style. Style 0 is `underline`,
+/// everything else (including unknown style) is `highlight`.
+fn annotation_type(style: Option<i64>, note: Option<&str>) -> &'static str {
+    match (style, note) {
+        (_, Some(_)) => "note",
+        (Some(0), None) => "underline",
+        _ => "highlight",
+    }
+}
+
+/// Number of whitespace separated words in text
+fn word_count(text: &str) -> usize {
+    text.split_whitespace().count()
+}
+
+/// How far annotation database is ahead of the sync-file
+#[derive(Serialize)]
+struct SyncDrift {
+    last_sync: Option<DateTime<Utc>>,
+    latest_annotation: Option<DateTime<Utc>>,
+    /// Number of annotations created after the last sync (all annotations if never synced)
+    not_synced: usize,
+}
+
+impl SyncDrift {
+    fn new(last_sync: Option<DateTime<Utc>>, annotations: &[Annotation]) -> Self {
+        let not_synced = annotations
+            .iter()
+            .filter(|a| last_sync.is_none_or(|t| a.anotation_time > t))
+            .count();
+        Self {
+            last_sync,
+            latest_annotation: annotations.iter().map(|a| a.anotation_time).max(),
+            not_synced,
+        }
+    }
+}
+
+/// Annotation which book is not found in the library, see [`read_orphans`]
+#[derive(Serialize)]
+struct Orphan {
+    uuid: Option<String>,
+    asset_id: Option<String>,
+    selected_text: Option<String>,
+}
+
+/// Number of highlights in every part of a book, see [`Density::new`]
+#[derive(Serialize)]
+struct BookDensity {
+    title: String,
+    asset_id: String,
+    /// Number of highlights in every bin, from the beginning of the book to the end
+    bins: Vec<usize>,
+    /// Number of highlights without location
+    unknown: usize,
+}
+
+/// Highlight density of all the books
+#[derive(Serialize)]
+struct Density(Vec<BookDensity>);
+
+impl Density {
+    /// Splits highlights of every book into bins by their position in the book
+    ///
+    /// The number of chapters of a book is not stored by iBooks, so the position is the spine
+    /// item (chapter file) of the highlight relative to the last chapter with highlights.
+    fn new(annotations: &[Annotation], bins: usize) -> Self {
+        let mut books = BTreeMap::<_, Vec<_>>::new();
+        for a in annotations {
+            let spine = a.location.as_deref().and_then(cfi_spine_index);
+            books
+                .entry((&a.book_title, &a.asset_id))
+                .or_default()
+                .push(spine);
+        }
+        let books = books.into_iter().map(|((title, asset_id), spines)| {
+            let length = spines.iter().flatten().max().map_or(1, |max| max + 1);
+            let mut counts = vec![0; bins];
+            for spine in spines.iter().flatten() {
+                counts[(spine * bins as u64 / length) as usize] += 1;
+            }
+            BookDensity {
+                title: title.clone(),
+                asset_id: asset_id.clone(),
+                bins: counts,
+                unknown: spines.iter().filter(|s| s.is_none()).count(),
+            }
+        });
+        Self(books.collect())
+    }
+}
+
+/// Summary statistics of exported annotations
+#[derive(Serialize)]
+struct Stats {
+    annotations: usize,
+    books: usize,
+    notes: usize,
+    words: usize,
+    /// Average number of words per highlight
+    average_words: f64,
+}
+
+impl Stats {
+    fn new(annotations: &[Annotation]) -> Self {
+        let books = annotations
+            .iter()
+            .map(|a| &a.asset_id)
+            .collect::<HashSet<_>>()
+            .len();
+        let words = annotations.iter().map(|a| a.word_count).sum::<usize>();
+        let average_words = if annotations.is_empty() {
+            0.
+        } else {
+            words as f64 / annotations.len() as f64
+        };
+        Self {
+            annotations: annotations.len(),
+            books,
+            notes: annotations.iter().filter(|a| a.note.is_some()).count(),
+            words,
+            average_words,
+        }
+    }
+}
+
+/// Counts annotations in each group, most common groups first (ties ordered by group name)
+///
+/// Months are taken in given `timezone`.
+fn count_annotations(
+    annotations: &[Annotation],
+    count_by: CountBy,
+    books: &HashMap<String, Book>,
+    timezone: Timezone,
+) -> Vec<(String, usize)> {
+    let mut counts = HashMap::<String, usize>::new();
+    for a in annotations {
+        let group = match count_by {
+            CountBy::Book => a.book_title.clone(),
+            CountBy::Color => a.color.map(HighlightColor::name).unwrap_or("none").into(),
+            CountBy::Month => timezone
+                .convert(a.anotation_time)
+                .format("%Y-%m")
+                .to_string(),
+            CountBy::Language => books
+                .get(&a.asset_id)
+                .and_then(|b| b.language.clone())
+                .unwrap_or_else(|| "unknown".into()),
+        };
+        *counts.entry(group).or_default() += 1;
+    }
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by(|(a_group, a_count), (b_group, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_group.cmp(b_group))
+    });
+    counts
+}
+
+/// Merges highlights of a passage made in several selections
+///
+/// Annotations of each book are ordered by location, and an annotation is merged into the
+/// previous one if it was created no later than `window` after (or before) it. Merged
+/// texts are joined with a space and notes with a new line. Merged annotation keeps the
+/// time and location of the first part. Result is ordered by time.
+fn merge_consecutive(mut annotations: Vec<Annotation>, window: Duration) -> Vec<Annotation> {
+    annotations.sort_by_cached_key(|a| {
+        let location = a.location.as_deref().map(cfi_sort_key);
+        (a.asset_id.clone(), location)
+    });
+
+    let mut merged = Vec::<Annotation>::with_capacity(annotations.len());
+    // Creation time of the last part of the last merged annotation
+    let mut last_time: Option<DateTime<Utc>> = None;
+    for a in annotations {
+        let time = a.anotation_time;
+        let adjacent = |prev: &Annotation| {
+            prev.asset_id == a.asset_id
+                && last_time.is_some_and(|t| {
+                    let delta = time - t;
+                    delta <= window && -delta <= window
+                })
+        };
+        match merged.last_mut() {
+            Some(prev) if adjacent(prev) => {
+                prev.selected_text.push(' ');
+                prev.selected_text.push_str(&a.selected_text);
+                prev.word_count += a.word_count;
+                prev.modified_at = prev.modified_at.max(a.modified_at);
+                prev.location_end = a.location_end;
+                prev.note = match (prev.note.take(), a.note) {
+                    (Some(p), Some(n)) => Some(format!("{}\n{}", p, n)),
+                    (p, n) => p.or(n),
+                };
+            }
+            _ => merged.push(a),
+        }
+        last_time = Some(time);
+    }
+    merged.sort_by_key(|a| a.anotation_time);
+    merged
+}
+
+/// Longest highlights (by number of characters) first, stable for highlights of the same length
+fn sort_by_length(annotations: &mut [Annotation]) {
+    annotations.sort_by_key(|a| Reverse(a.selected_text.chars().count()));
+}
+
+/// Skips `offset` annotations and keeps at most `limit` of the rest
+///
+/// Returns kept annotations and creation time of the earliest dropped annotation.
+fn paginate(
+    mut annotations: Vec<Annotation>,
+    offset: usize,
+    limit: Option<usize>,
+) -> (Vec<Annotation>, Option<DateTime<Utc>>) {
+    let mut page = annotations.split_off(offset.min(annotations.len()));
+    let rest = page.split_off(limit.unwrap_or(usize::MAX).min(page.len()));
+    let first_dropped = annotations
+        .iter()
+        .chain(&rest)
+        .map(|a| a.anotation_time)
+        .min();
+    (page, first_dropped)
+}
+
+/// Keeps only first `max` annotations of every book
+///
+/// Returns kept and dropped annotations, both in original order.
+fn limit_per_book(annotations: Vec<Annotation>, max: usize) -> (Vec<Annotation>, Vec<Annotation>) {
+    let mut counts = HashMap::<String, usize>::new();
+    let (mut kept, mut dropped) = (vec![], vec![]);
+    for a in annotations {
+        let count = counts.entry(a.asset_id.clone()).or_default();
+        if *count < max {
+            *count += 1;
+            kept.push(a);
+        } else {
+            dropped.push(a);
+        }
+    }
+    (kept, dropped)
+}
+
+/// Keeps only the latest modified version of annotations with the same UUID (eg. read from
+/// several snapshots of the database)
+///
+/// Returns kept annotations in original order and the number of dropped duplicates.
+fn dedupe_by_uuid(annotations: Vec<Annotation>) -> (Vec<Annotation>, usize) {
+    let mut best = HashMap::<&str, usize>::new();
+    for (idx, a) in annotations.iter().enumerate() {
+        let Some(uuid) = a.uuid.as_deref() else {
+            continue;
+        };
+        let better = best
+            .get(uuid)
+            .is_none_or(|&b| a.modified_at >= annotations[b].modified_at);
+        if better {
+            best.insert(uuid, idx);
+        }
+    }
+    let kept = best.into_values().collect::<HashSet<_>>();
+    let total = annotations.len();
+    let annotations = annotations
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, a)| a.uuid.is_none() || kept.contains(idx))
+        .map(|(_, a)| a)
+        .collect::<Vec<_>>();
+    let duplicates = total - annotations.len();
+    (annotations, duplicates)
+}
+
+/// Keeps only one annotation among annotations of a book with the same selected text
+///
+/// Annotation with the longest note is kept, so intermediate versions of an edited note are
+/// dropped. If notes are of the same length, the latest annotation is kept. Order of the
+/// kept annotations is preserved.
+fn dedupe_by_note(annotations: Vec<Annotation>) -> Vec<Annotation> {
+    let note_len = |a: &Annotation| a.note.as_deref().map(str::len).unwrap_or(0);
+    // Index of the best annotation for every (book, selected text) pair
+    let mut best = HashMap::<(&str, &str), usize>::new();
+    for (idx, a) in annotations.iter().enumerate() {
+        let key = (a.asset_id.as_str(), a.selected_text.as_str());
+        let better = best.get(&key).is_none_or(|&b| {
+            let b = &annotations[b];
+            (note_len(a), a.anotation_time) >= (note_len(b), b.anotation_time)
+        });
+        if better {
+            best.insert(key, idx);
+        }
+    }
+    let kept = best.into_values().collect::<HashSet<_>>();
+    annotations
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _)| kept.contains(idx))
+        .map(|(_, a)| a)
+        .collect()
+}
+
+/// Key for ordering EPUB CFI locations (eg. `epubcfi(/6/4[chap01]!/4/2,/1:0,/1:15)`)
+///
+/// All the numeric steps of the CFI in order. Element ids in brackets are ignored.
+fn cfi_sort_key(cfi: &str) -> Vec<u64> {
+    let mut without_ids = String::with_capacity(cfi.len());
+    let mut depth = 0;
+    for c in cfi.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            c if depth == 0 => without_ids.push(c),
+            _ => {}
+        }
+    }
+    without_ids
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|n| n.parse().ok())
+        .collect()
+}
+
+/// Index of the spine item (chapter file) of EPUB CFI location, counting from 0
+///
+/// `epubcfi(/6/4[ch1]!/4/2,/1:0,/1:15)` is in spine item 1 (spine steps are even numbers).
+fn cfi_spine_index(cfi: &str) -> Option<u64> {
+    let step = *cfi_sort_key(cfi).get(1)?;
+    (step >= 2).then(|| step / 2 - 1)
+}
+
+/// Splits EPUB CFI range into start and end CFIs
+///
+/// `epubcfi(/6/4[ch1]!/4/2,/1:0,/1:15)` is `epubcfi(/6/4[ch1]!/4/2/1:0)` to
+/// `epubcfi(/6/4[ch1]!/4/2/1:15)`. Returns `None` if the location is not a range.
+fn cfi_range(cfi: &str) -> Option<(String, String)> {
+    let range = cfi.strip_prefix("epubcfi(")?.strip_suffix(')')?;
+    // Element ids in brackets may contain commas
+    let mut parts = vec![];
+    let (mut depth, mut start) = (0, 0);
+    for (idx, c) in range.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&range[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&range[start..]);
+    let [parent, start, end] = parts.as_slice() else {
+        return None;
+    };
+    Some((
+        format!("epubcfi({}{})", parent, start),
+        format!("epubcfi({}{})", parent, end),
+    ))
+}
+
+/// Filters annotations by book
+///
+/// Book matches if its title contains any of `include` patterns or its title or asset id is
+/// equal to one of `listed` entries (or both are empty), title matches any of `globs` (or
+/// `globs` is empty) and contains none of `exclude` patterns. All comparisons are
+/// case-insensitive.
+struct BookFilter {
+    include: Vec<String>,
+    listed: Vec<String>,
+    exclude: Vec<String>,
+    globs: Vec<Pattern>,
+}
+
+impl BookFilter {
+    fn new(include: &[String], listed: &[String], exclude: &[String], globs: &[Pattern]) -> Self {
+        let lowercase = |patterns: &[String]| patterns.iter().map(|p| p.to_lowercase()).collect();
+        Self {
+            include: lowercase(include),
+            listed: lowercase(listed),
+            exclude: lowercase(exclude),
+            globs: globs.to_vec(),
+        }
+    }
+
+    fn matches(&self, title: &str, asset_id: &str) -> bool {
+        let glob_options = MatchOptions {
+            case_sensitive: false,
+            ..MatchOptions::new()
+        };
+        let globs_match = self.globs.is_empty()
+            || self
+                .globs
+                .iter()
+                .any(|g| g.matches_with(title, glob_options));
+
+        let title = title.to_lowercase();
+        if self.exclude.iter().any(|p| title.contains(p.as_str())) {
+            return false;
+        }
+        let asset_id = asset_id.to_lowercase();
+        let include_match = (self.include.is_empty() && self.listed.is_empty())
+            || self.include.iter().any(|p| title.contains(p.as_str()))
+            || self.listed.iter().any(|e| *e == title || *e == asset_id);
+        include_match && globs_match
+    }
+}
+
+/// Expands `text` to the boundaries of sentences it's part of in `context`
+///
+/// Sentences are assumed to end with `.`, `!`, `?` or `…`, so abbreviations may cut a sentence
+/// short. Returns `None` if `text` is not found in `context`.
+fn snap_to_sentence(text: &str, context: &str) -> Option<String> {
+    let is_end = |c: char| matches!(c, '.' | '!' | '?' | '…');
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    let start = context.find(text)?;
+    let end = start + text.len();
+    let sentence_start = context[..start]
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| is_end(c))
+        .map(|(idx, c)| idx + c.len_utf8())
+        .unwrap_or(0);
+    let sentence_end = if text.ends_with(is_end) {
+        end
+    } else {
+        context[end..]
+            .char_indices()
+            .find(|&(_, c)| is_end(c))
+            .map(|(idx, c)| end + idx + c.len_utf8())
+            .unwrap_or(context.len())
+    };
+    Some(context[sentence_start..sentence_end].trim().to_string())
+}
+
+/// Numbers annotations in the order books are rendered (`--book-order`)
+fn number_annotations(
+    annotations: &mut [Annotation],
+    options: &format::Options,
+    scope: NumberScope,
+) {
+    let order = format::group_by_book(annotations.iter(), options)
+        .into_iter()
+        .map(|book| book.into_iter().map(|a| a.slug.clone()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let mut numbers = HashMap::new();
+    let mut next = 1;
+    for book in order {
+        if scope == NumberScope::Book {
+            next = 1;
+        }
+        for slug in book {
+            numbers.insert(slug, next);
+            next += 1;
+        }
+    }
+    for a in annotations {
+        a.number = numbers.get(&a.slug).copied();
+    }
+}
+
+/// Trims whitespace around text and a single trailing `…`
+///
+/// Text consisting of the ellipsis only is kept as is, so the highlight doesn't become empty.
+fn trim_ellipsis(text: &str) -> String {
+    let text = text.trim();
+    match text.strip_suffix('…').map(str::trim_end) {
+        Some(trimmed) if !trimmed.is_empty() => trimmed.to_string(),
+        _ => text.to_string(),
+    }
+}
+
+/// Transliterates text to ASCII, emoji are removed
+fn to_ascii(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii() {
+            result.push(c);
+        } else if !is_emoji(c) {
+            result.push_str(deunicode::deunicode_char(c).unwrap_or_default());
+        }
+    }
+    result
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(
+        c,
+        '\u{1F000}'..='\u{1FAFF}' | '\u{2600}'..='\u{27BF}' | '\u{FE00}'..='\u{FE0F}' | '\u{200D}'
+    )
+}
+
+/// Reads `--book-file` entries: one book title or asset id per line
+///
+/// Blank lines and lines starting with `#` are ignored.
+fn read_book_file(path: &Path) -> Result<Vec<String>> {
+    let content =
+        fs::read_to_string(path).context(Errors::UnableToReadBookFile(path.to_path_buf()))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Reads annotations from JSON export (`--format json` without `--fields` and `--envelope`)
+fn read_json_export(path: &Path) -> Result<Vec<Annotation>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let content =
+        fs::read_to_string(path).context(Errors::UnableToReadExport(path.to_path_buf()))?;
+    let mut annotations: Vec<Annotation> =
+        serde_json::from_str(&content).context(Errors::UnableToReadExport(path.to_path_buf()))?;
+    // Exports made before slugs were introduced
+    for a in annotations.iter_mut().filter(|a| a.slug.is_empty()) {
+        a.slug = annotation_slug(a.uuid.as_deref(), &a.book_title, &a.selected_text);
+    }
+    Ok(annotations)
+}
+
+/// Filter on annotation properties
+///
+/// Values within a category are combined with OR (any of the colors), categories are
+/// combined with AND. Empty category matches all annotations.
+struct AnnotationFilter {
+    colors: Vec<HighlightColor>,
+    with_notes: bool,
+    favorites_only: bool,
+    /// Lowercase parts of author names
+    authors: Vec<String>,
+}
+
+impl AnnotationFilter {
+    fn matches(&self, a: &Annotation) -> bool {
+        let author_match = self.authors.is_empty()
+            || a.author.as_ref().is_some_and(|author| {
+                let author = author.to_lowercase();
+                self.authors.iter().any(|p| author.contains(p.as_str()))
+            });
+        let color_match =
+            self.colors.is_empty() || a.color.is_some_and(|c| self.colors.contains(&c));
+        let note_match = !self.with_notes || a.note.is_some();
+        let favorite_match = !self.favorites_only || a.favorited == Some(true);
+        color_match && note_match && favorite_match && author_match
+    }
+}
+
+/// Writes output to a file (with trailing newline, same as on stdout)
+///
+/// Returns `false` if file already has the same content and was not written, so
+/// modification time stays intact.
+fn write_output(path: &Path, output: &str, encoding: OutputEncoding) -> Result<bool> {
+    let content = encoding.encode(&format!("{}\n", output));
+    if fs::read(path).ok().as_deref() == Some(content.as_slice()) {
+        return Ok(false);
+    }
+    write_atomically(path, &content).context(Errors::UnableToWriteOutput(path.to_path_buf()))?;
+    Ok(true)
+}
+
+/// Prints output to stdout (with trailing newline) in given encoding
+fn print_output(output: &str, encoding: OutputEncoding) -> Result<()> {
+    let content = encoding.encode(&format!("{}\n", output));
+    io::stdout().write_all(&content)?;
+    Ok(())
+}
+
+/// Writes file through a temporary file in the same directory renamed into place
+///
+/// Readers see either old or new content, never a partially written file (eg. if export is
+/// interrupted).
+fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+    let result = fs::File::create(&tmp).and_then(|mut file| {
+        file.write_all(content)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Copies text to the system clipboard using `pbcopy`
+fn copy_to_clipboard(text: &str) -> Result<()> {
+    let mut pbcopy = Command::new("pbcopy")
+        .stdin(Stdio::piped())
+        .spawn()
+        .context(Errors::UnableToCopyToClipboard)?;
+    if let Some(stdin) = pbcopy.stdin.as_mut() {
+        stdin
+            .write_all(text.as_bytes())
+            .context(Errors::UnableToCopyToClipboard)?;
+    }
+    let status = pbcopy.wait().context(Errors::UnableToCopyToClipboard)?;
+    if !status.success() {
+        return Err(Errors::UnableToCopyToClipboard.into());
+    }
+    Ok(())
+}
+
+/// Runs `--formatter-cmd` with `sh -c`, writing `input` to its stdin
+///
+/// Returns stdout of the command without trailing newline, the output is written with one.
+fn run_formatter(cmd: &str, input: &str) -> Result<String> {
+    let failed = || Errors::FormatterCommandFailed(cmd.to_string());
+    let mut child = Command::new("sh")
+        .args(["-c", cmd])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(failed)?;
+    // Writing from another thread, so the command can't block on full stdout pipe
+    let mut stdin = child.stdin.take().ok_or_else(failed)?;
+    let input = input.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output().with_context(failed)?;
+    // Command may exit without reading all the input, it's not an error
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(failed().into());
+    }
+    let output = String::from_utf8(output.stdout).with_context(failed)?;
+    Ok(output.strip_suffix('\n').unwrap_or(&output).to_string())
+}
+
+/// Progress bar on stderr, hidden unless enabled and stderr is a terminal
+fn progress_bar(enabled: bool, len: usize) -> ProgressBar {
+    if !enabled || !io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let style = ProgressStyle::with_template("{bar:40} {pos}/{len} annotations")
+        .expect("Invalid progress bar template");
+    ProgressBar::new(len as u64).with_style(style)
+}
+
+/// Writes annotations to SQLite database with normalized schema
+///
+/// Database is created if needed. `books` and `annotations` tables are dropped and recreated,
+/// so the database always reflects the last export.
+fn write_sqlite(path: &Path, annotations: &[Annotation], progress: &ProgressBar) -> Result<()> {
+    let mut connection = Connection::open(path)?;
+    let tx = connection.transaction()?;
+    tx.execute_batch(
+        "DROP TABLE IF EXISTS annotations;
+         DROP TABLE IF EXISTS books;
+         CREATE TABLE books (
+            asset_id TEXT PRIMARY KEY,
+            title TEXT NOT NULL,
+            author TEXT
+         );
+         CREATE TABLE annotations (
+            id INTEGER PRIMARY KEY,
+            asset_id TEXT NOT NULL REFERENCES books (asset_id),
+            selected_text TEXT NOT NULL,
+            note TEXT,
+            created_at TEXT NOT NULL,
+            location TEXT,
+            color TEXT,
+            uuid TEXT
+         );",
+    )?;
+    {
+        let mut insert_book =
+            tx.prepare("INSERT OR IGNORE INTO books (asset_id, title, author) VALUES (?, ?, ?)")?;
+        let mut insert_annotation = tx.prepare(
+            "INSERT INTO annotations
+                (asset_id, selected_text, note, created_at, location, color, uuid)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        for a in annotations {
+            insert_book.execute(params![a.asset_id, a.book_title, a.author])?;
+            insert_annotation.execute(params![
+                a.asset_id,
+                a.selected_text,
+                a.note,
+                a.anotation_time.to_rfc3339(),
+                a.location,
+                a.color.map(HighlightColor::name),
+                a.uuid,
+            ])?;
+            progress.inc(1);
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Current version of the sync-file format
+///
+/// - version 1 – single line with the time of last synced annotation (RFC3339 or Unix
+///   timestamp, see [`SyncFormat`]);
+/// - version 2 – same first line, followed by `key=value` properties: `version` and
+///   `last_uuid` (UUID of last synced annotation). Time is kept on the first line, so
+///   scripts reading the first line of the file still work.
+///
+/// Version 1 files are read transparently and rewritten in the current format on update.
+const SYNC_FILE_VERSION: u32 = 2;
+
+/// State saved in the sync-file
+struct SyncState {
+    /// Creation time of the last synced annotation
+    time: DateTime<Utc>,
+    /// UUID of the last synced annotation
+    last_uuid: Option<String>,
+}
+
+impl From<&Annotation> for SyncState {
+    fn from(a: &Annotation) -> Self {
+        Self {
+            time: a.anotation_time,
+            last_uuid: a.uuid.clone(),
+        }
+    }
+}
+
+/// Parses Unix timestamp with optional fractional part (eg. `1671854200.5`)
+///
+/// Fraction is parsed as nanoseconds, not as a float, so precision is not lost.
+fn parse_epoch(value: &str) -> Option<DateTime<Utc>> {
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let seconds = seconds.parse::<i64>().ok()?;
+    if fraction.len() > 9 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = format!("{:0<9}", fraction).parse::<u32>().ok()?;
+    Utc.timestamp_opt(seconds, nanos).single()
+}
+
+/// Directory where program state (sync-files, `profiles`, `schema` and `timezones` files) is
+/// stored
+///
+/// Directory is not created here, so read-only runs leave no traces on the filesystem.
+fn state_dir() -> Result<PathBuf> {
+    let state_dir = dirs::data_dir().ok_or(Errors::UnableToFindProgramLocation)?;
+    Ok(state_dir.join("ibooks-export"))
+}
+
+struct LastSyncFile(PathBuf);
+
+impl LastSyncFile {
+    /// Sync-file of the default profile is `last_sync`, named profiles use `last_sync.<name>`
+    fn find(profile: Option<&str>) -> Result<Self> {
+        let file_name = match profile {
+            Some(profile) => format!("last_sync.{}", profile),
+            None => "last_sync".to_string(),
+        };
+        Ok(Self(state_dir()?.join(file_name)))
+    }
+
+    pub fn read(&self) -> Result<Option<SyncState>> {
+        if !Path::new(&self.0).exists() {
+            return Ok(None);
+        }
+
+        let data = fs::read(&self.0).context(Errors::UnableToReadSyncFile)?;
+
+        let string = String::from_utf8(data).context(Errors::UnableToReadSyncFile)?;
+        let mut lines = string.lines().map(str::trim).filter(|l| !l.is_empty());
+
+        // Format is detected automatically, so files written with any `--sync-format` can be read
+        // Empty file is created by `--init`, nothing was synced yet
+        let Some(timestamp) = lines.next() else {
+            return Ok(None);
+        };
+        let time = if let Some(time) = parse_epoch(timestamp) {
+            time
+        } else {
+            DateTime::parse_from_rfc3339(timestamp)
+                .context(Errors::UnableToReadSyncFile)?
+                .with_timezone(&Utc)
+        };
+
+        // Version 1 files have no properties, so they are read as is and upgraded on next update
+        let mut state = SyncState {
+            time,
+            last_uuid: None,
+        };
+        for line in lines {
+            let (key, value) = line.split_once('=').ok_or(Errors::UnableToReadSyncFile)?;
+            match key.trim() {
+                "version" => {
+                    let version = value.trim().parse().context(Errors::UnableToReadSyncFile)?;
+                    if version > SYNC_FILE_VERSION {
+                        return Err(Errors::UnsupportedSyncFileVersion(version).into());
+                    }
+                }
+                "last_uuid" => state.last_uuid = Some(value.trim().to_string()),
+                // Properties unknown to this version are ignored
+                _ => {}
+            }
+        }
+        Ok(Some(state))
+    }
+
+    /// Path of the sync-file backup: `<sync-file>.bak`
+    fn backup_path(&self) -> PathBuf {
+        let mut path = self.0.clone().into_os_string();
+        path.push(".bak");
+        path.into()
+    }
+
+    /// Copies sync-file to the backup, nothing is done if there is no sync-file yet
+    pub fn backup(&self) -> Result<()> {
+        if self.0.exists() {
+            debug!("Backing up sync-file to {:?}", self.backup_path());
+            fs::copy(&self.0, self.backup_path()).context(Errors::UnableToWriteSyncFile)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces sync-file with its backup (the backup is kept)
+    pub fn restore(&self) -> Result<()> {
+        let backup = self.backup_path();
+        if !backup.exists() {
+            return Err(Errors::SyncBackupNotFound(backup).into());
+        }
+        fs::copy(&backup, &self.0).context(Errors::UnableToWriteSyncFile)?;
+        Ok(())
+    }
+
+    /// Creates state directory of the sync-file before it's written for the first time
+    fn create_dir(&self) -> Result<()> {
+        if let Some(dir) = self.0.parent() {
+            fs::create_dir_all(dir).context(Errors::UnableToWriteSyncFile)?;
+        }
+        Ok(())
+    }
+
+    /// Creates empty sync-file if it doesn't exist yet
+    ///
+    /// Returns `false` if file already exists and was left intact.
+    pub fn init(&self) -> Result<bool> {
+        if self.0.exists() {
+            return Ok(false);
+        }
+        self.create_dir()?;
+        fs::write(&self.0, "").context(Errors::UnableToWriteSyncFile)?;
+        Ok(true)
+    }
+
+    pub fn update(&self, state: &SyncState, format: SyncFormat) -> Result<()> {
+        let mut content = match format {
+            SyncFormat::Rfc3339 => state.time.to_rfc3339(),
+            SyncFormat::Epoch => match state.time.timestamp_subsec_nanos() {
+                0 => state.time.timestamp().to_string(),
+                nanos => format!("{}.{:09}", state.time.timestamp(), nanos),
+            },
+        };
+        content.push_str(&format!("\nversion={}\n", SYNC_FILE_VERSION));
+        if let Some(uuid) = &state.last_uuid {
+            content.push_str(&format!("last_uuid={}\n", uuid));
+        }
+        self.create_dir()?;
+        fs::write(&self.0, content).context(Errors::UnableToWriteSyncFile)
+    }
+}
+
+/// Columns of the annotation table stored in the state dir to notice iBooks schema changes
+///
+/// Stored in the `schema` file (`schema.<name>` for named profiles), one `table.column` per line.
+struct SchemaFingerprint(PathBuf);
+
+impl SchemaFingerprint {
+    fn find(profile: Option<&str>) -> Result<Self> {
+        let file_name = match profile {
+            Some(profile) => format!("schema.{}", profile),
+            None => "schema".to_string(),
+        };
+        Ok(Self(state_dir()?.join(file_name)))
+    }
+
+    /// Warns if `columns` are different from the stored ones
+    ///
+    /// Fingerprint is stored with `accept`, or if there is none yet and `remember` is set.
+    fn check(&self, columns: &[String], accept: bool, remember: bool) -> Result<()> {
+        // Missing table is reported by the schema check
+        if columns.is_empty() {
+            return Ok(());
+        }
+        let stored = fs::read_to_string(&self.0).ok().map(|content| {
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        });
+        match stored {
+            Some(stored) if stored == columns => Ok(()),
+            Some(stored) if !accept => {
+                let added = columns.iter().filter(|c| !stored.contains(c));
+                let removed = stored.iter().filter(|c| !columns.contains(c));
+                let list = |columns: Vec<&String>| match columns.is_empty() {
+                    true => "none".to_string(),
+                    false => columns
+                        .iter()
+                        .map(|c| c.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                };
+                eprintln!(
+                    "Warning: schema of the annotation database changed since it was remembered, \
+                    probably because of iBooks update. Export results may be affected.\n\
+                    Added columns: {}\nRemoved columns: {}\n\
+                    Check the export and run with --accept-schema to remember the new schema.",
+                    list(added.collect()),
+                    list(removed.collect())
+                );
+                Ok(())
+            }
+            None if !accept && !remember => Ok(()),
+            _ => {
+                debug!("Storing schema fingerprint: {:?}", self.0);
+                let write = || -> io::Result<()> {
+                    if let Some(dir) = self.0.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                    fs::write(&self.0, columns.join("\n") + "\n")
+                };
+                write().context(Errors::UnableToWriteSchemaFingerprint(self.0.clone()))
+            }
+        }
+    }
+}
+
+/// Sorted `table.column` names of the annotation table of the database
+fn annotation_schema(annotation_db: impl AsRef<Path>) -> Result<Vec<String>> {
+    let connection = open_read_only(annotation_db)?;
+    let Some(layout) = AnnotationLayout::detect(&connection, "main")? else {
+        return Ok(vec![]);
+    };
+    let mut columns = table_columns(&connection, "main", layout.table())?
+        .into_iter()
+        .map(|c| format!("{}.{}", layout.table(), c))
+        .collect::<Vec<_>>();
+    columns.sort();
+    Ok(columns)
+}
+
+/// Locates iBooks container directory
+///
+/// Default container is located in the home dir. Containers of named profiles are listed
+/// in the `profiles` file in the state dir, one `name = path` pair per line.
+fn locate_container(profile: Option<&str>, bundle_id: &str) -> Result<PathBuf> {
+    let Some(profile) = profile else {
+        let home = dirs::home_dir().ok_or(Errors::NoHomeDir)?;
+        return Ok(home.join("Library/Containers").join(bundle_id));
+    };
+
+    let profiles_file = state_dir()?.join("profiles");
+    let content = fs::read_to_string(&profiles_file).unwrap_or_default();
+    let container = content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| l.split_once('='))
+        .find(|(name, _)| name.trim() == profile)
+        .map(|(_, path)| PathBuf::from(path.trim()));
+    container.ok_or_else(|| Errors::UnknownProfile(profile.to_string(), profiles_file).into())
+}
+
+/// Annotation databases (`--annotation-db` if given) and the library database
+fn databases(args: &Args) -> Result<(Vec<PathBuf>, Option<PathBuf>)> {
+    let (annotation_dbs, library_db) = match &args.ios_backup {
+        Some(backup) => locate_backup_databases(backup)?,
+        None => {
+            let container = container(args)?;
+            debug!("iBooks container location: {:?}", &container);
+            (
+                locate_annotation_databases(&container)?,
+                locate_library_database(&container)?,
+            )
+        }
+    };
+    if args.annotation_db.is_empty() {
+        Ok((annotation_dbs, library_db))
+    } else {
+        Ok((expand_globs(&args.annotation_db)?, library_db))
+    }
+}
+
+/// iBooks container selected with `--library-root`, `--profile` and `--bundle-id`
+fn container(args: &Args) -> Result<PathBuf> {
+    match &args.library_root {
+        Some(root) => Ok(library_root_container(root, &args.bundle_id)),
+        None => locate_container(args.profile.as_deref(), &args.bundle_id),
+    }
+}
+
+/// iBooks container for `--library-root`
+///
+/// Root can be either the `com.apple.iBooksX` container itself or a directory containing
+/// `Library/Containers/com.apple.iBooksX` (eg. home dir on another volume).
+fn library_root_container(root: &Path, bundle_id: &str) -> PathBuf {
+    let nested = root.join("Library/Containers").join(bundle_id);
+    if !root.join("Data/Documents").exists() && nested.exists() {
+        nested
+    } else {
+        root.to_path_buf()
+    }
+}
+
+/// Locates annotation databases
+///
+/// Usually there is a single database, but on some setups iBooks splits annotations between
+/// several database files in the same directory. First database is the primary one.
+fn locate_annotation_databases(container: &Path) -> Result<Vec<PathBuf>> {
+    locate_databases(container.join("Data/Documents/AEAnnotation"))
+}
+
+fn locate_library_database(container: &Path) -> Result<Option<PathBuf>> {
+    let databases = locate_databases(container.join("Data/Documents/BKLibrary"))?;
+    Ok(databases.into_iter().next())
+}
+
+/// Modification time of SQLite database including its write-ahead log
+///
+/// Recent changes may be only in the `-wal` file until SQLite checkpoints it. Returns `None`
+/// if modification time can't be read.
+fn last_modified(db: &Path) -> Option<DateTime<Utc>> {
+    let mut wal = db.as_os_str().to_owned();
+    wal.push("-wal");
+    let mtime = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let db_mtime = mtime(db)?;
+    let mtime = mtime(Path::new(&wal)).map_or(db_mtime, |wal| wal.max(db_mtime));
+    Some(mtime.into())
+}
+
+/// Locates annotation and library databases in iOS backup made by Finder or iTunes
+///
+/// Files of a backup are stored under hashed names (`ab/abcdef...`), original paths are listed
+/// in `Manifest.db`. Databases are recognized by the directory they were in on the device.
+fn locate_backup_databases(backup: &Path) -> Result<(Vec<PathBuf>, Option<PathBuf>)> {
+    let error = || Errors::UnableToReadBackupManifest(backup.to_path_buf());
+    let connection = open_read_only(backup.join("Manifest.db")).with_context(error)?;
+    let mut stmt = connection
+        .prepare(
+            "select fileID, relativePath from Files
+             where relativePath like '%.sqlite'
+             order by relativePath",
+        )
+        .with_context(error)?;
+    let files = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut annotation_dbs = vec![];
+    let mut library_db = None;
+    for (file_id, relative_path) in files {
+        let Some(prefix) = file_id.get(..2) else {
+            continue;
+        };
+        let path = backup.join(prefix).join(&file_id);
+        let dir = Path::new(&relative_path)
+            .parent()
+            .and_then(Path::file_name)
+            .and_then(OsStr::to_str);
+        debug!("Backup file {} is {:?}", relative_path, path);
+        match dir {
+            Some("AEAnnotation") => annotation_dbs.push(path),
+            Some("BKLibrary") => {
+                library_db.get_or_insert(path);
+            }
+            _ => {}
+        }
+    }
+    Ok((annotation_dbs, library_db))
+}
+
+/// Expands glob patterns (plain paths are kept as is), every pattern has to match
+fn expand_globs(patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for pattern in patterns {
+        let mut matched = glob::glob(pattern)?.collect::<Result<Vec<_>, _>>()?;
+        if matched.is_empty() {
+            return Err(Errors::NoDatabaseMatched(pattern.clone()).into());
+        }
+        matched.sort();
+        paths.extend(matched);
+    }
+    // Patterns may overlap
+    let mut seen = HashSet::new();
+    paths.retain(|p| seen.insert(p.clone()));
+    Ok(paths)
+}
+
+/// Lists all SQLite databases in a directory ordered by name
+fn locate_databases(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let mut databases = vec![];
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        let extension = path.extension().and_then(OsStr::to_str).unwrap_or("");
+        if extension == "sqlite" {
+            databases.push(path);
+        }
+    }
+    databases.sort();
+
+    Ok(databases)
+}
+
+/// Converts Core Data timestamp (fractional seconds since 2001-01-01) keeping sub-second precision
+///
+/// Returns `None` for timestamps out of the range of representable dates (or not finite).
+fn core_data_to_timestamp(ts: f64) -> Option<DateTime<Utc>> {
+    if !ts.is_finite() {
+        return None;
+    }
+    let seconds = ts.floor();
+    let nanos = (((ts - seconds) * 1e9).round() as u32).min(999_999_999);
+    let seconds = (seconds as i64).checked_add(978307200)?;
+    Utc.timestamp_opt(seconds, nanos).single()
+}
+
+fn timestamp_to_core_data(time: DateTime<Utc>) -> f64 {
+    (time.timestamp() - 978307200) as f64 + f64::from(time.timestamp_subsec_nanos()) / 1e9
+}
+
+/// Timezone of human readable dates
+#[derive(Clone, Copy, Debug, Default)]
+enum Timezone {
+    /// System timezone
+    #[default]
+    Local,
+    /// Explicitly given `--tz` or `--utc`
+    Fixed(FixedOffset),
+}
+
+impl Timezone {
+    fn convert(self, time: DateTime<Utc>) -> DateTime<FixedOffset> {
+        match self {
+            Timezone::Local => {
+                let local = time.with_timezone(&Local);
+                local.with_timezone(local.offset())
+            }
+            Timezone::Fixed(offset) => time.with_timezone(&offset),
+        }
+    }
+}
+
+/// Local time with seconds precision as shown in tables and UI
+fn local_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+mod format {
+    use super::*;
+    use chrono::SecondsFormat;
+    use icu_collator::{options::CollatorOptions, CollatorBorrowed};
+    use serde::{ser::Error as _, Serializer};
+    use std::{cmp::Reverse, collections::BTreeMap, fmt};
+    use term_table::{
+        row::Row,
+        table_cell::{string_width, Alignment, TableCell},
+        TableStyle,
+    };
+
+    /// Locale specific date format and collation
+    pub(crate) struct Locale {
+        dates: chrono::Locale,
+        collator: CollatorBorrowed<'static>,
+    }
+
+    impl Locale {
+        /// Locale for a POSIX (`de_DE`) or BCP 47 (`de-DE`) code
+        pub(crate) fn new(code: &str) -> Result<Self> {
+            let dates = chrono::Locale::try_from(code.replace('-', "_").as_str())
+                .map_err(|_| Errors::UnsupportedLocale(code.to_string()))?;
+            let locale = icu_locale_core::Locale::try_from_str(&code.replace('_', "-"))
+                .map_err(|_| Errors::UnsupportedLocale(code.to_string()))?;
+            let collator = CollatorBorrowed::try_new((&locale).into(), CollatorOptions::default())
+                .map_err(|_| Errors::UnsupportedLocale(code.to_string()))?;
+            Ok(Self { dates, collator })
+        }
+    }
+
+    /// Options shared by all formats
+    pub(crate) struct Options {
+        /// Text rendered instead of an empty highlight
+        pub empty_text: String,
+        /// Render human readable dates relative to `now`
+        pub relative_dates: bool,
+        /// Reference time for relative dates
+        pub now: DateTime<Utc>,
+        /// Annotation fields included in JSON formats (all if empty)
+        pub fields: Vec<String>,
+        /// Tint table rows using ANSI color of the highlight
+        pub color_rows: bool,
+        /// Separator between highlights in book summary format
+        pub summary_separator: String,
+        /// Whether note is rendered as parent or child block of the quote in Logseq format
+        pub note_position: NotePosition,
+        /// Order of books in formats grouping annotations by book
+        pub book_order: BookOrder,
+        /// Show lengths of text and note in table format
+        pub show_lengths: bool,
+        /// Split annotations of a book by color in markdown formats
+        pub group_by_color: bool,
+        /// Time books were last opened, required for [`BookOrder::Recent`]
+        pub last_opened: HashMap<String, DateTime<Utc>>,
+        /// Cover images of books, rendered in HTML format
+        pub covers: HashMap<String, PathBuf>,
+        /// Render a shell command opening the highlight in iBooks
+        pub with_open_command: bool,
+        /// Skip quotes of highlights without text in Logseq format
+        pub compact_logseq: bool,
+        /// Add color as a block property of quotes in Logseq format
+        pub logseq_color_property: bool,
+        /// Render BibTeX entries of books in markdown formats using given book metadata
+        pub bibtex: Option<HashMap<String, Book>>,
+        /// Serialize annotation time as Unix timestamp in JSON formats
+        pub epoch_timestamps: bool,
+        /// Serialize missing notes as `null` instead of skipping the field
+        pub include_null_notes: bool,
+        /// Wrap JSON annotations in an object with run metadata
+        pub envelope: bool,
+        /// Group JSON annotations by month (`YYYY-MM` in local timezone)
+        pub group_by_month: bool,
+        /// Omit title of annotations nested in books
+        pub json_compact_books: bool,
+        /// Number of spaces JSON is indented with, compact JSON if 0
+        pub json_indent: usize,
+        /// Omit CSV header row
+        pub no_header: bool,
+        /// Collapse newlines and tabs of highlight text in table format
+        pub collapse_whitespace: bool,
+        /// Render annotation context beneath the quote in markdown formats
+        pub with_context: bool,
+        /// Render legend of colors and color tags in markdown formats
+        pub color_legend: bool,
+        /// Meanings of colors in the legend
+        pub color_labels: Vec<(HighlightColor, String)>,
+        /// Line written before each annotation in markdown formats (nothing if empty)
+        pub block_prefix: String,
+        /// Line written after each annotation in markdown formats (nothing if empty)
+        pub block_suffix: String,
+        /// Written before notes in markdown and HTML formats
+        pub note_prefix: String,
+        /// Escape Markdown special characters in highlights and notes of markdown formats
+        pub escape_markdown: bool,
+        /// Written between annotations in markdown formats instead of the format default
+        pub annotation_separator: Option<String>,
+        /// Written between books in markdown formats instead of the format default
+        pub book_separator: Option<String>,
+        /// Template of book headings
+        pub heading_format: String,
+        /// Template of the Logseq block all books are nested under
+        pub logseq_parent: Option<String>,
+        /// Tags of Hugo and Jekyll posts
+        pub post_tags: Vec<String>,
+        /// Cite highlighted text after notes in notes format
+        pub notes_with_quotes: bool,
+        /// Render highlights with the same text once with links to all their books
+        pub collapse_identical: bool,
+        /// Locale for dates and book titles order
+        pub locale: Option<Locale>,
+        /// Timezone of human readable dates
+        pub timezone: Timezone,
+    }
+
+    impl Default for Options {
+        fn default() -> Self {
+            Self {
+                empty_text: "-".into(),
+                relative_dates: false,
+                now: Utc::now(),
+                fields: vec![],
+                color_rows: false,
+                summary_separator: "\n\n".into(),
+                note_position: NotePosition::Above,
+                book_order: BookOrder::Alpha,
+                show_lengths: false,
+                group_by_color: false,
+                last_opened: HashMap::new(),
+                covers: HashMap::new(),
+                with_open_command: false,
+                compact_logseq: false,
+                logseq_color_property: false,
+                bibtex: None,
+                epoch_timestamps: false,
+                include_null_notes: false,
+                envelope: false,
+                group_by_month: false,
+                json_compact_books: false,
+                json_indent: 0,
+                no_header: false,
+                collapse_whitespace: false,
+                with_context: false,
+                color_legend: false,
+                color_labels: vec![],
+                block_prefix: String::new(),
+                block_suffix: String::new(),
+                note_prefix: String::new(),
+                escape_markdown: false,
+                annotation_separator: None,
+                book_separator: None,
+                heading_format: "{title}".into(),
+                logseq_parent: None,
+                post_tags: vec!["highlights".into()],
+                notes_with_quotes: false,
+                collapse_identical: false,
+                locale: None,
+                timezone: Timezone::Local,
+            }
+        }
+    }
+
+    impl Options {
+        fn text<'a>(&'a self, a: &'a Annotation) -> &'a str {
+            if a.selected_text.trim().is_empty() {
+                &self.empty_text
+            } else {
+                &a.selected_text
+            }
+        }
+
+        /// Highlight text for markdown formats, escaped with `--escape-markdown`
+        ///
+        /// `--empty-text` is used as is, so it still can be a markdown snippet.
+        fn markdown_text<'a>(&'a self, a: &'a Annotation) -> Cow<'a, str> {
+            let text = if self.escape_markdown && !a.selected_text.trim().is_empty() {
+                Cow::Owned(escape_markdown(&a.selected_text))
+            } else {
+                Cow::Borrowed(self.text(a))
+            };
+            match a.number {
+                Some(n) => Cow::Owned(format!("[{}] {}", n, text)),
+                None => text,
+            }
+        }
+
+        /// Note for markdown formats with `--note-prefix`, escaped with `--escape-markdown`
+        fn markdown_note(&self, note: &str) -> String {
+            if self.escape_markdown {
+                self.note(&escape_markdown(note))
+            } else {
+                self.note(note)
+            }
+        }
+
+        /// Highlight text for table cell, single line if `--collapse-whitespace` is set
+        fn table_text(&self, a: &Annotation) -> String {
+            let mut text = if self.collapse_whitespace {
+                a.selected_text
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            } else {
+                a.selected_text.clone()
+            };
+            if let Some(n) = a.number {
+                text.insert_str(0, &format!("[{}] ", n));
+            }
+            if self.with_open_command {
+                text.push('\n');
+                text.push_str(&a.open_command());
+            }
+            text
+        }
+
+        /// Context rendered beneath the quote, if enabled with `--with-context`
+        fn context<'a>(&self, a: &'a Annotation) -> Option<&'a str> {
+            a.context.as_deref().filter(|_| self.with_context)
+        }
+
+        /// Book heading from `--heading-format`, `title` is the title as rendered by the format
+        fn heading(&self, annotations: &[&Annotation], title: &str) -> String {
+            let book = annotations[0];
+            let mut heading = String::with_capacity(self.heading_format.len());
+            let mut rest = self.heading_format.as_str();
+            // Single pass, so placeholders in values (eg. "{count}" in a title) are kept as is
+            while let Some(start) = rest.find('{') {
+                heading.push_str(&rest[..start]);
+                rest = &rest[start..];
+                let Some(end) = rest.find('}') else {
+                    break;
+                };
+                match &rest[1..end] {
+                    "title" => heading.push_str(title),
+                    "author" => heading.push_str(book.author.as_deref().unwrap_or_default()),
+                    "asset_id" => heading.push_str(&book.asset_id),
+                    "count" => heading.push_str(&annotations.len().to_string()),
+                    _ => heading.push_str(&rest[..=end]),
+                }
+                rest = &rest[end + 1..];
+            }
+            heading.push_str(rest);
+            heading
+        }
+
+        /// Colors used by annotations with their labels, in the order of [`HighlightColor`]
+        fn legend(&self, annotations: &[Annotation]) -> Vec<(HighlightColor, Option<&str>)> {
+            let used = annotations
+                .iter()
+                .filter_map(|a| a.color)
+                .collect::<Vec<_>>();
+            HighlightColor::value_variants()
+                .iter()
+                .filter(|c| used.contains(c))
+                .map(|&c| {
+                    let label = self.color_labels.iter().rev().find(|(l, _)| *l == c);
+                    (c, label.map(|(_, label)| label.as_str()))
+                })
+                .collect()
+        }
+
+        /// Annotations of a book split by color for `--group-by-color-within-book`
+        ///
+        /// Groups are in the order of [`HighlightColor`] (highlights without color go last) and
+        /// have a heading with color name and its label. Returns a single group without heading
+        /// if grouping is off or none of the annotations has a color.
+        fn color_groups<'a>(
+            &self,
+            annotations: Vec<&'a Annotation>,
+        ) -> Vec<(Option<String>, Vec<&'a Annotation>)> {
+            if !self.group_by_color || annotations.iter().all(|a| a.color.is_none()) {
+                return vec![(None, annotations)];
+            }
+            let mut groups = HighlightColor::value_variants()
+                .iter()
+                .map(|&c| Some(c))
+                .chain([None])
+                .map(|color| (color, vec![]))
+                .collect::<Vec<_>>();
+            for a in annotations {
+                if let Some((_, group)) = groups.iter_mut().find(|(c, _)| *c == a.color) {
+                    group.push(a);
+                }
+            }
+            groups
+                .into_iter()
+                .filter(|(_, group)| !group.is_empty())
+                .map(|(color, group)| {
+                    let heading = match color {
+                        Some(color) => {
+                            let label = self.color_labels.iter().rev().find(|(l, _)| *l == color);
+                            match label {
+                                Some((_, label)) => format!("{} ({})", color.name(), label),
+                                None => color.name().to_string(),
+                            }
+                        }
+                        None => "no color".to_string(),
+                    };
+                    (Some(heading), group)
+                })
+                .collect()
+        }
+
+        /// BibTeX entry of the book of annotation, if `--with-bibtex` is set
+        ///
+        /// Cite key is author last name followed by year (eg. `author2021`), books with the
+        /// same key get a letter suffix in order of appearance, `keys` are the keys taken so far.
+        fn bibtex(&self, a: &Annotation, keys: &mut HashSet<String>) -> Option<String> {
+            let book = self.bibtex.as_ref()?.get(&a.asset_id);
+            let year = book.and_then(|b| b.year.as_deref());
+            let last_name = a
+                .author
+                .as_deref()
+                .and_then(|author| author.split(['&', ',', ';']).next())
+                .and_then(|author| author.split_whitespace().last());
+            let key_base = slugify(last_name.unwrap_or(&a.book_title)).replace('-', "")
+                + year.unwrap_or_default();
+            let mut key = key_base.clone();
+            for suffix in 'b'..='z' {
+                if keys.insert(key.clone()) {
+                    break;
+                }
+                key = format!("{}{}", key_base, suffix);
+            }
+
+            let mut fields = vec![("title", a.book_title.as_str())];
+            if let Some(author) = &a.author {
+                fields.push(("author", author));
+            }
+            if let Some(year) = year {
+                fields.push(("year", year));
+            }
+            if let Some(publisher) = book.and_then(|b| b.publisher.as_deref()) {
+                fields.push(("publisher", publisher));
+            }
+            let fields = fields
+                .into_iter()
+                .map(|(name, value)| format!("  {} = {{{}}}", name, escape_bibtex(value)))
+                .collect::<Vec<_>>();
+            Some(format!("@book{{{},\n{}\n}}", key, fields.join(",\n")))
+        }
+
+        /// Note with `--note-prefix`
+        fn note(&self, note: &str) -> String {
+            format!("{}{}", self.note_prefix, note)
+        }
+
+        /// Color tag of the annotation (eg. ` #yellow`), if `--color-legend` is set
+        fn color_tag(&self, a: &Annotation) -> String {
+            match a.color {
+                Some(color) if self.color_legend => format!(" #{}", color.name()),
+                _ => String::new(),
+            }
+        }
+
+        /// Human readable annotation time
+        fn time(&self, a: &Annotation) -> String {
+            if self.relative_dates {
+                humanize(self.now - a.anotation_time)
+            } else if let Some(locale) = &self.locale {
+                let time = self.timezone.convert(a.anotation_time);
+                time.format_localized("%x %X", locale.dates).to_string()
+            } else {
+                let time = self.timezone.convert(a.anotation_time);
+                time.format("%Y-%m-%d %H:%M:%S").to_string()
+            }
+        }
+
+        /// Writes annotation block wrapped with `--block-prefix`/`--block-suffix` lines
+        fn block(
+            &self,
+            f: &mut fmt::Formatter<'_>,
+            indent: &str,
+            body: impl FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result,
+        ) -> fmt::Result {
+            if !self.block_prefix.is_empty() {
+                writeln!(f, "{}{}", indent, self.block_prefix)?;
+            }
+            body(f)?;
+            if !self.block_suffix.is_empty() {
+                writeln!(f, "{}{}", indent, self.block_suffix)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Groups annotations by book, books are ordered with `--book-order`
+    ///
+    /// Every group is non-empty and annotations within a group keep their original order.
+    pub(crate) fn group_by_book<'a>(
+        annotations: impl IntoIterator<Item = &'a Annotation>,
+        options: &Options,
+    ) -> Vec<Vec<&'a Annotation>> {
+        let mut books = BTreeMap::<_, Vec<_>>::new();
+        for a in annotations {
+            books
+                .entry((a.book_title.as_str(), a.asset_id.as_str()))
+                .or_default()
+                .push(a);
+        }
+        // BTreeMap is ordered by title, so it's a tie breaker for other orders (sort is stable)
+        let mut books = books.into_values().collect::<Vec<_>>();
+        if let Some(locale) = &options.locale {
+            books.sort_by(|a, b| locale.collator.compare(&a[0].book_title, &b[0].book_title));
+        }
+        match options.book_order {
+            BookOrder::Alpha => {}
+            BookOrder::Count => books.sort_by_key(|b| Reverse(b.len())),
+            BookOrder::Recent => {
+                books.sort_by_key(|b| Reverse(options.last_opened.get(&b[0].asset_id)))
+            }
+            BookOrder::Added => {
+                books.sort_by_key(|b| b.iter().map(|a| a.anotation_time).min());
+            }
+        }
+        books
+    }
+
+    /// Highlights with the same text grouped across books (`--collapse-identical`)
+    ///
+    /// Text is compared with surrounding whitespace trimmed. Groups are in order of their first
+    /// highlight.
+    fn identical_groups<'a>(
+        annotations: impl IntoIterator<Item = &'a Annotation>,
+    ) -> Vec<Vec<&'a Annotation>> {
+        let mut groups = Vec::<Vec<_>>::new();
+        let mut index = HashMap::<&str, usize>::new();
+        for a in annotations {
+            match index.get(a.selected_text.trim()) {
+                Some(&idx) => groups[idx].push(a),
+                None => {
+                    index.insert(a.selected_text.trim(), groups.len());
+                    groups.push(vec![a]);
+                }
+            }
+        }
+        groups
+    }
+
+    /// Page links to the books of highlights, every book is linked once, eg. `[[Book 1]] [[Book 2]]`
+    fn book_links(annotations: &[&Annotation]) -> String {
+        let mut links = vec![];
+        for a in annotations {
+            let link = format!("[[{}]]", a.book_title);
+            if !links.contains(&link) {
+                links.push(link);
+            }
+        }
+        links.join(" ")
+    }
+
+    /// Book file written to `--output-dir`
+    pub(crate) struct IndexEntry {
+        pub title: String,
+        /// File name without extension, used for wiki links
+        pub stem: String,
+        pub file_name: String,
+        /// Number of highlights in the file
+        pub count: usize,
+    }
+
+    /// Table of contents of `--output-dir`
+    ///
+    /// Logseq and Obsidian resolve pages by file name, so wiki links are used for them. Markdown
+    /// links are used for all other formats.
+    pub(crate) struct BookIndex(pub Vec<IndexEntry>, pub OutputFormat);
+
+    impl fmt::Display for BookIndex {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(f, "# Books")?;
+            writeln!(f)?;
+            for (idx, book) in self.0.iter().enumerate() {
+                if idx > 0 {
+                    writeln!(f)?;
+                }
+                let link = match self.1 {
+                    OutputFormat::Logseq | OutputFormat::Obsidian if book.stem == book.title => {
+                        format!("[[{}]]", book.title)
+                    }
+                    OutputFormat::Logseq => format!("[{}]([[{}]])", book.title, book.stem),
+                    OutputFormat::Obsidian => format!("[[{}|{}]]", book.stem, book.title),
+                    _ => format!("[{}](<{}>)", book.title, book.file_name),
+                };
+                write!(f, "- {} ({} highlights)", link, book.count)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Formats duration as a relative time like "3 days ago" (or "in 3 days" for negative durations)
+    fn humanize(duration: Duration) -> String {
+        let seconds = duration.num_seconds();
+        let units = [
+            ("year", 365 * 24 * 3600),
+            ("month", 30 * 24 * 3600),
+            ("day", 24 * 3600),
+            ("hour", 3600),
+            ("minute", 60),
+        ];
+        let Some((unit, count)) = units
+            .iter()
+            .map(|(unit, size)| (unit, seconds.abs() / size))
+            .find(|(_, count)| *count > 0)
+        else {
+            return "just now".into();
+        };
+        let plural = if count > 1 { "s" } else { "" };
+        if seconds > 0 {
+            format!("{} {}{} ago", count, unit, plural)
+        } else {
+            format!("in {} {}{}", count, unit, plural)
+        }
+    }
+
+    /// Json format for annotations
+    pub(crate) struct Json<'a>(pub Vec<Annotation>, pub &'a Options);
+
+    impl Json<'_> {
+        /// Formats annotations, unlike [`fmt::Display`] serialization error is returned to the caller
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            let annotations = self.0.iter().map(|a| Projected(a, self.1));
+            let annotations = annotations.collect::<Vec<_>>();
+            if self.1.group_by_month {
+                let mut months = BTreeMap::<_, Vec<_>>::new();
+                for a in annotations {
+                    let month = a.1.timezone.convert(a.0.anotation_time).format("%Y-%m");
+                    months.entry(month.to_string()).or_default().push(a);
+                }
+                Ok(to_json(&months, self.1)?)
+            } else if self.1.envelope {
+                let envelope = Envelope {
+                    generated_at: self.1.now,
+                    source: "ibooks",
+                    count: annotations.len(),
+                    annotations,
+                };
+                Ok(to_json(&envelope, self.1)?)
+            } else {
+                Ok(to_json(&annotations, self.1)?)
+            }
+        }
+    }
+
+    impl fmt::Display for Json<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// YAML format for annotations
+    ///
+    /// Same structure as [`Json`] (including `--envelope`), multi-line highlights are written
+    /// as block scalars.
+    pub(crate) struct Yaml<'a>(pub Vec<Annotation>, pub &'a Options);
+
+    impl Yaml<'_> {
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            let annotations = self.0.iter().map(|a| Projected(a, self.1));
+            let annotations = annotations.collect::<Vec<_>>();
+            let result = if self.1.envelope {
+                serde_yaml::to_string(&Envelope {
+                    generated_at: self.1.now,
+                    source: "ibooks",
+                    count: annotations.len(),
+                    annotations,
+                })
+            } else {
+                serde_yaml::to_string(&annotations)
+            };
+            Ok(result?.trim_end().to_string())
+        }
+    }
+
+    impl fmt::Display for Yaml<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// Run metadata wrapped around JSON annotations with `--envelope`
+    ///
+    /// ```json
+    /// {"generated_at": "...", "source": "ibooks", "count": 1, "annotations": [...]}
+    /// ```
+    #[derive(Serialize)]
+    struct Envelope<'a> {
+        generated_at: DateTime<Utc>,
+        source: &'static str,
+        count: usize,
+        annotations: Vec<Projected<'a, Annotation>>,
+    }
+
+    /// Json format with annotations nested in books
+    ///
+    /// ```json
+    /// [{"title": "Book 1", "asset_id": "...", "date_added": "...", "last_opened": "...",
+    ///   "annotations": [...]}]
+    /// ```
+    /// Books are ordered by title.
+    pub(crate) struct BooksJson<'a>(
+        pub Vec<Annotation>,
+        pub &'a HashMap<String, Book>,
+        pub &'a Options,
+    );
+
+    #[derive(Serialize)]
+    struct BookAnnotations<'a> {
+        title: &'a str,
+        asset_id: &'a str,
+        #[serde(flatten)]
+        book: Option<&'a Book>,
+        annotations: Vec<BookAnnotation<'a>>,
+    }
+
+    /// Annotation nested in a book, title is omitted with `--json-compact-books`
+    struct BookAnnotation<'a>(Projected<'a, Annotation>);
+
+    impl Serialize for BookAnnotation<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            if !self.0 .1.json_compact_books {
+                return self.0.serialize(serializer);
+            }
+            let mut value = serde_json::to_value(&self.0).map_err(S::Error::custom)?;
+            if let serde_json::Value::Object(map) = &mut value {
+                map.remove("book_title");
+            }
+            value.serialize(serializer)
+        }
+    }
+
+    impl BooksJson<'_> {
+        fn books(&self) -> Vec<BookAnnotations<'_>> {
+            group_by_book(&self.0, self.2)
+                .into_iter()
+                .map(|annotations| {
+                    let first = annotations[0];
+                    BookAnnotations {
+                        title: &first.book_title,
+                        asset_id: &first.asset_id,
+                        book: self.1.get(&first.asset_id),
+                        annotations: annotations
+                            .into_iter()
+                            .map(|a| BookAnnotation(Projected(a, self.2)))
+                            .collect(),
+                    }
+                })
+                .collect()
+        }
+
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            Ok(to_json(&self.books(), self.2)?)
+        }
+    }
+
+    impl fmt::Display for BooksJson<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// [`BooksJson`] with every book written as a separate JSON object on its own line
+    ///
+    /// ```json
+    /// {"title": "Book 1", "asset_id": "...", "annotations": [...]}
+    /// {"title": "Book 2", "asset_id": "...", "annotations": [...]}
+    /// ```
+    pub(crate) struct BooksJsonl<'a>(
+        pub Vec<Annotation>,
+        pub &'a HashMap<String, Book>,
+        pub &'a Options,
+    );
+
+    impl BooksJsonl<'_> {
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            let books = BooksJson(self.0.clone(), self.1, self.2);
+            let lines = books
+                .books()
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(lines.join("\n"))
+        }
+    }
+
+    impl fmt::Display for BooksJsonl<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// [W3C Web Annotation](https://www.w3.org/TR/annotation-model/) format
+    ///
+    /// JSON array with an `AnnotationCollection` for every book. Highlights are targeted with
+    /// EPUB CFI fragment selector (if location is known) and text quote selector, notes are
+    /// textual bodies
+    /// ```json
+    /// [{"@context": "http://www.w3.org/ns/anno.jsonld", "type": "AnnotationCollection",
+    ///   "label": "Book 1", "total": 1, "first": {"type": "AnnotationPage", "items": [
+    ///     {"type": "Annotation", "motivation": "highlighting", "target": {
+    ///       "source": "ibooks://assetid/...", "selector": [...]}}]}}]
+    /// ```
+    pub(crate) struct WebAnnotation<'a>(pub Vec<Annotation>, pub &'a Options);
+
+    const WEB_ANNOTATION_CONTEXT: &str = "http://www.w3.org/ns/anno.jsonld";
+    const EPUB_CFI_SPEC: &str = "http://www.idpf.org/epub/linking/cfi/epub-cfi.html";
+
+    #[derive(Serialize)]
+    struct AnnotationCollection<'a> {
+        #[serde(rename = "@context")]
+        context: &'static str,
+        #[serde(rename = "type")]
+        kind: &'static str,
+        label: &'a str,
+        total: usize,
+        first: AnnotationPage<'a>,
+    }
+
+    #[derive(Serialize)]
+    struct AnnotationPage<'a> {
+        #[serde(rename = "type")]
+        kind: &'static str,
+        items: Vec<W3cAnnotation<'a>>,
+    }
+
+    #[derive(Serialize)]
+    struct W3cAnnotation<'a> {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(rename = "type")]
+        kind: &'static str,
+        motivation: &'static str,
+        created: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        modified: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        body: Option<TextualBody<'a>>,
+        target: Target<'a>,
+    }
+
+    #[derive(Serialize)]
+    struct TextualBody<'a> {
+        #[serde(rename = "type")]
+        kind: &'static str,
+        value: &'a str,
+        format: &'static str,
+    }
+
+    #[derive(Serialize)]
+    struct Target<'a> {
+        source: String,
+        selector: Vec<Selector<'a>>,
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "type")]
+    enum Selector<'a> {
+        FragmentSelector {
+            #[serde(rename = "conformsTo")]
+            conforms_to: &'static str,
+            value: &'a str,
+        },
+        TextQuoteSelector {
+            exact: &'a str,
+        },
+    }
+
+    impl WebAnnotation<'_> {
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            let time = |t: DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::Secs, true);
+            let books = group_by_book(&self.0, self.1)
+                .into_iter()
+                .map(|annotations| {
+                    let items = annotations.iter().map(|a| {
+                        let mut selector = vec![];
+                        if let Some(location) = &a.location {
+                            selector.push(Selector::FragmentSelector {
+                                conforms_to: EPUB_CFI_SPEC,
+                                value: location,
+                            });
+                        }
+                        if !a.selected_text.trim().is_empty() {
+                            selector.push(Selector::TextQuoteSelector {
+                                exact: &a.selected_text,
+                            });
+                        }
+                        W3cAnnotation {
+                            id: a.uuid.as_ref().map(|uuid| format!("urn:uuid:{}", uuid)),
+                            kind: "Annotation",
+                            motivation: match a.note {
+                                Some(_) => "commenting",
+                                None => "highlighting",
+                            },
+                            created: time(a.created_at),
+                            modified: a.modified_at.map(time),
+                            body: a.note.as_deref().map(|note| TextualBody {
+                                kind: "TextualBody",
+                                value: note,
+                                format: "text/plain",
+                            }),
+                            target: Target {
+                                source: format!("ibooks://assetid/{}", a.asset_id),
+                                selector,
+                            },
+                        }
+                    });
+                    AnnotationCollection {
+                        context: WEB_ANNOTATION_CONTEXT,
+                        kind: "AnnotationCollection",
+                        label: &annotations[0].book_title,
+                        total: annotations.len(),
+                        first: AnnotationPage {
+                            kind: "AnnotationPage",
+                            items: items.collect(),
+                        },
+                    }
+                });
+            Ok(to_json(&books.collect::<Vec<_>>(), self.1)?)
+        }
+    }
+
+    impl fmt::Display for WebAnnotation<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// Json format with all highlights of a book joined into a single text
+    ///
+    /// ```json
+    /// [{"title": "Book 1", "author": "...", "text": "annotation 1\n\nannotation 2"}]
+    /// ```
+    /// Books are ordered by title, highlights are joined with `--summary-separator`.
+    pub(crate) struct BookSummary<'a>(pub Vec<Annotation>, pub &'a Options);
+
+    #[derive(Serialize)]
+    struct Summary<'a> {
+        title: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        author: Option<&'a str>,
+        text: String,
+    }
+
+    impl BookSummary<'_> {
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            let books = group_by_book(&self.0, self.1);
+            let summaries = books.into_iter().map(|annotations| {
+                let first = annotations[0];
+                let texts = annotations.iter().map(|a| self.1.text(a));
+                Summary {
+                    title: &first.book_title,
+                    author: first.author.as_deref(),
+                    text: texts.collect::<Vec<_>>().join(&self.1.summary_separator),
+                }
+            });
+            Ok(to_json(&summaries.collect::<Vec<_>>(), self.1)?)
+        }
+    }
+
+    impl fmt::Display for BookSummary<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// Serializes only given fields of the object (all fields if none given)
+    ///
+    /// With `--epoch-timestamps` annotation times are serialized as Unix timestamps, with `--tz`
+    /// they are converted to the given offset. `--include-null-notes` adds `note: null` to
+    /// annotations without a note.
+    struct Projected<'a, T>(&'a T, &'a Options);
+
+    impl<T: Serialize> Serialize for Projected<'_, T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let fields = &self.1.fields;
+            // JSON timestamps are in UTC unless an offset is given explicitly
+            let offset = match self.1.timezone {
+                Timezone::Fixed(offset) if offset.local_minus_utc() != 0 => Some(offset),
+                _ => None,
+            };
+            let null_notes = self.1.include_null_notes
+                && (fields.is_empty() || fields.iter().any(|f| f == "note"));
+            if fields.is_empty() && !self.1.epoch_timestamps && offset.is_none() && !null_notes {
+                return self.0.serialize(serializer);
+            }
+            let mut value = serde_json::to_value(self.0).map_err(S::Error::custom)?;
+            if let serde_json::Value::Object(map) = &mut value {
+                if !fields.is_empty() {
+                    map.retain(|key, _| fields.contains(key));
+                }
+                if null_notes {
+                    map.entry("note").or_insert(serde_json::Value::Null);
+                }
+                for key in ["anotation_time", "created_at", "modified_at"] {
+                    let Some(time) = map.get_mut(key) else {
+                        continue;
+                    };
+                    let Some(parsed) = time
+                        .as_str()
+                        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                    else {
+                        continue;
+                    };
+                    if self.1.epoch_timestamps {
+                        *time = parsed.timestamp().into();
+                    } else if let Some(offset) = offset {
+                        let parsed = parsed.with_timezone(&offset);
+                        *time = parsed.to_rfc3339_opts(SecondsFormat::AutoSi, true).into();
+                    }
+                }
+            }
+            value.serialize(serializer)
+        }
+    }
+
+    /// Error of formats based on serde serialization
+    ///
+    /// Those formats provide `try_to_string()`, which returns this error, while `Display`
+    /// implementation can only log it.
+    #[derive(Error, Debug)]
+    pub(crate) enum FormatError {
+        #[error("Unable to format JSON: {0}")]
+        Json(#[from] serde_json::Error),
+
+        #[error("Unable to format YAML: {0}")]
+        Yaml(#[from] serde_yaml::Error),
+    }
+
+    /// Serializes value to JSON indented with `--json-indent` spaces
+    fn to_json<T: Serialize>(value: &T, options: &Options) -> serde_json::Result<String> {
+        if options.json_indent == 0 {
+            return serde_json::to_string(value);
+        }
+        let indent = " ".repeat(options.json_indent);
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+        let mut json = vec![];
+        value.serialize(&mut serde_json::Serializer::with_formatter(
+            &mut json, formatter,
+        ))?;
+        Ok(String::from_utf8(json).expect("JSON is always valid UTF-8"))
+    }
+
+    fn write_result(
+        f: &mut fmt::Formatter<'_>,
+        result: Result<String, FormatError>,
+    ) -> fmt::Result {
+        match result {
+            Ok(output) => write!(f, "{}", output),
+            Err(e) => {
+                error!("{}", e);
+                Err(fmt::Error)
+            }
+        }
+    }
+
+    /// Titles of books, one per line, followed by author unless `raw` is set
+    pub(crate) struct BookList<'a>(pub Vec<(&'a String, Option<&'a str>)>, pub bool);
+
+    impl fmt::Display for BookList<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for (title, author) in &self.0 {
+                match author.filter(|_| !self.1) {
+                    Some(author) => writeln!(f, "{} — {}", title, author)?,
+                    None => writeln!(f, "{}", title)?,
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Number of annotations in each group, one group per line
+    pub(crate) struct Counts(pub Vec<(String, usize)>);
+
+    impl fmt::Display for Counts {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for (group, count) in &self.0 {
+                writeln!(f, "{:>6}  {}", count, group)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Number of annotations in each group as JSON array: `[{"group": "...", "count": 1}]`
+    pub(crate) struct CountsJson(pub Vec<(String, usize)>);
+
+    #[derive(Serialize)]
+    struct GroupCount<'a> {
+        group: &'a str,
+        count: usize,
+    }
+
+    impl CountsJson {
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            let counts = self.0.iter().map(|(group, count)| GroupCount {
+                group,
+                count: *count,
+            });
+            Ok(serde_json::to_string(&counts.collect::<Vec<_>>())?)
+        }
+    }
+
+    impl fmt::Display for CountsJson {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// Summary statistics, one value per line
+    pub(crate) struct StatsText<'a>(pub &'a Stats);
+
+    impl fmt::Display for StatsText<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let stats = self.0;
+            writeln!(f, "Annotations:         {}", stats.annotations)?;
+            writeln!(f, "Books:               {}", stats.books)?;
+            writeln!(f, "Notes:               {}", stats.notes)?;
+            writeln!(f, "Words highlighted:   {}", stats.words)?;
+            writeln!(f, "Words per highlight: {:.1}", stats.average_words)
+        }
+    }
+
+    /// Summary statistics as JSON object
+    pub(crate) struct StatsJson<'a>(pub &'a Stats);
+
+    impl StatsJson<'_> {
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            Ok(serde_json::to_string(self.0)?)
+        }
+    }
+
+    impl fmt::Display for StatsJson<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// Highlight density as a histogram of every book
+    ///
+    /// ```text
+    /// Book 1
+    ///     0-50%  ███ 3
+    ///   50-100%  █ 1
+    /// ```
+    pub(crate) struct DensityText<'a>(pub &'a Density);
+
+    impl fmt::Display for DensityText<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for (idx, book) in self.0 .0.iter().enumerate() {
+                if idx > 0 {
+                    writeln!(f)?;
+                }
+                writeln!(f, "{}", book.title)?;
+                let bins = book.bins.len();
+                for (bin, count) in book.bins.iter().enumerate() {
+                    let range = format!("{}-{}%", bin * 100 / bins, (bin + 1) * 100 / bins);
+                    writeln!(f, "  {:>8}  {} {}", range, "█".repeat(*count), count)?;
+                }
+                if book.unknown > 0 {
+                    writeln!(f, "  {:>8}  {}", "unknown", book.unknown)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Highlight density as JSON array of books
+    pub(crate) struct DensityJson<'a>(pub &'a Density);
+
+    impl DensityJson<'_> {
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            Ok(serde_json::to_string(self.0)?)
+        }
+    }
+
+    impl fmt::Display for DensityJson<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// Orphaned annotations, one per line: uuid, asset id and the first line of the text
+    pub(crate) struct OrphansText<'a>(pub &'a [Orphan]);
+
+    impl fmt::Display for OrphansText<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for orphan in self.0 {
+                let text = orphan.selected_text.as_deref().unwrap_or_default();
+                writeln!(
+                    f,
+                    "{}  {}  {}",
+                    orphan.uuid.as_deref().unwrap_or("-"),
+                    orphan.asset_id.as_deref().unwrap_or("-"),
+                    text.lines().next().unwrap_or_default()
+                )?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Orphaned annotations as JSON array
+    pub(crate) struct OrphansJson<'a>(pub &'a [Orphan]);
+
+    impl OrphansJson<'_> {
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            Ok(serde_json::to_string(self.0)?)
+        }
+    }
+
+    impl fmt::Display for OrphansJson<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// Metadata of the library books as JSON array
+    pub(crate) struct LibraryJson<'a>(pub &'a [LibraryEntry]);
+
+    impl LibraryJson<'_> {
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            Ok(serde_json::to_string(self.0)?)
+        }
+    }
+
+    impl fmt::Display for LibraryJson<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// Sync-file drift report, one value per line
+    pub(crate) struct SyncDriftText<'a>(pub &'a SyncDrift);
+
+    impl fmt::Display for SyncDriftText<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let drift = self.0;
+            let time = |t: Option<DateTime<Utc>>| t.map(local_time);
+            let last_sync = time(drift.last_sync).unwrap_or_else(|| "never".into());
+            let latest = time(drift.latest_annotation).unwrap_or_else(|| "none".into());
+            writeln!(f, "Last sync:           {}", last_sync)?;
+            writeln!(f, "Latest annotation:   {}", latest)?;
+            writeln!(f, "Not synced:          {}", drift.not_synced)
+        }
+    }
+
+    /// Sync-file drift report as JSON object
+    pub(crate) struct SyncDriftJson<'a>(pub &'a SyncDrift);
+
+    impl SyncDriftJson<'_> {
+        pub(crate) fn try_to_string(&self) -> Result<String, FormatError> {
+            Ok(serde_json::to_string(self.0)?)
+        }
+    }
+
+    impl fmt::Display for SyncDriftJson<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_result(f, self.try_to_string())
+        }
+    }
+
+    /// Obsidian format
+    ///
+    /// Formatting annotations in Obsidian markdown format like
+    /// ```markdown
+    /// [[Book Title 1]]
+    ///
+    /// Note text (if any)
+    /// > Annotation text
+    /// ---
+    /// [[Book Title 2]]
+    ///
+    /// Note text (if any)
+    /// > Annotation text
+    /// ```
+    pub(crate) struct Obsidian<'a>(pub Vec<Annotation>, pub &'a Options);
+
+    impl fmt::Display for Obsidian<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let annotation_separator = self.1.annotation_separator.as_deref().unwrap_or("\n");
+            let book_separator = self.1.book_separator.as_deref().unwrap_or("\n---\n\n");
+            let mut cite_keys = HashSet::new();
+            if self.1.color_legend {
+                writeln!(f, "Colors:")?;
+                for (color, label) in self.1.legend(&self.0) {
+                    match label {
+                        Some(label) => writeln!(f, "- #{}: {}", color.name(), label)?,
+                        None => writeln!(f, "- #{}", color.name())?,
+                    }
+                }
+                write!(f, "{}", book_separator)?;
+            }
+            if self.1.collapse_identical {
+                for (idx, group) in identical_groups(&self.0).into_iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, "{}", annotation_separator)?;
+                    }
+                    for line in self.1.markdown_text(group[0]).lines() {
+                        writeln!(f, "> {}", line)?;
+                    }
+                    writeln!(f, "{}", book_links(&group))?;
+                    for note in group.iter().filter_map(|a| a.note.as_deref()) {
+                        writeln!(f, "{}", self.1.markdown_note(note))?;
+                    }
+                }
+                return Ok(());
+            }
+            for (idx, annotations) in group_by_book(&self.0, self.1).into_iter().enumerate() {
+                if idx > 0 {
+                    write!(f, "{}", book_separator)?;
+                }
+                let title = format!("[[{}]]", annotations[0].book_title);
+                writeln!(f, "{}", self.1.heading(&annotations, &title))?;
+                writeln!(f)?;
+                if let Some(entry) = self.1.bibtex(annotations[0], &mut cite_keys) {
+                    writeln!(f, "```bibtex\n{}\n```", entry)?;
+                    writeln!(f)?;
+                }
+                let groups = self.1.color_groups(annotations);
+                for (group_idx, (heading, annotations)) in groups.into_iter().enumerate() {
+                    if group_idx > 0 {
+                        write!(f, "{}", annotation_separator)?;
+                    }
+                    if let Some(heading) = heading {
+                        writeln!(f, "### {}", heading)?;
+                        writeln!(f)?;
+                    }
+                    for (idx, a) in annotations.into_iter().enumerate() {
+                        if idx > 0 {
+                            write!(f, "{}", annotation_separator)?;
+                        }
+                        let text = self.1.markdown_text(a);
+                        self.1.block(f, "", |f| {
+                            if let Some(note) = &a.note {
+                                writeln!(f, "{}", self.1.markdown_note(note))?;
+                            }
+                            for line in text.lines() {
+                                writeln!(f, "> {}", line)?;
+                            }
+                            let tag = self.1.color_tag(a);
+                            if !tag.is_empty() {
+                                writeln!(f, "{}", tag.trim_start())?;
+                            }
+                            if let Some(context) = self.1.context(a) {
+                                writeln!(f)?;
+                                for line in context.lines() {
+                                    writeln!(f, "*{}*", line.trim())?;
+                                }
+                            }
+                            if self.1.with_open_command {
+                                writeln!(f)?;
+                                writeln!(f, "`{}`", a.open_command())?;
+                            }
+                            Ok(())
+                        })?;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Logseq format
+    ///
+    /// Formatting annotations in logseq format like
+    /// ```markdown
+    /// - [[Book 1]]
+    ///     - > annotation 1
+    ///     - > annotation 2
+    /// - [[Book 2]]
+    ///     - > annotation 1
+    /// ```
+    pub(crate) struct Logseq<'a>(pub Vec<Annotation>, pub &'a Options);
+
+    impl fmt::Display for Logseq<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let compact = self.1.compact_logseq;
+            let is_empty = |a: &Annotation| a.selected_text.trim().is_empty();
+            // In compact mode annotations without text and note would render as empty blocks
+            let annotations = self
+                .0
+                .iter()
+                .filter(|a| !(compact && is_empty(a) && a.note.is_none()));
+
+            let annotation_separator = self.1.annotation_separator.as_deref().unwrap_or_default();
+            let book_separator = self.1.book_separator.as_deref().unwrap_or_default();
+            // With a parent block every other block is nested one level deeper
+            let base = match &self.1.logseq_parent {
+                Some(parent) => {
+                    let date = self.1.timezone.convert(self.1.now).format("%Y-%m-%d");
+                    writeln!(f, "- {}", parent.replace("{date}", &date.to_string()))?;
+                    "\t\t"
+                }
+                None => "",
+            };
+            let child = format!("{}\t\t", base);
+            let mut cite_keys = HashSet::new();
+            let grandchild = format!("{}\t\t\t", base);
+            if self.1.color_legend {
+                writeln!(f, "{}- Colors", base)?;
+                for (color, label) in self.1.legend(&self.0) {
+                    match label {
+                        Some(label) => writeln!(f, "{}- #{}: {}", child, color.name(), label)?,
+                        None => writeln!(f, "{}- #{}", child, color.name())?,
+                    }
+                }
+                write!(f, "{}", book_separator)?;
+            }
+            if self.1.collapse_identical {
+                for (idx, group) in identical_groups(annotations).into_iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, "{}", annotation_separator)?;
+                    }
+                    writeln!(f, "{}- > {}", base, self.1.markdown_text(group[0]))?;
+                    writeln!(f, "{}- {}", child, book_links(&group))?;
+                    for note in group.iter().filter_map(|a| a.note.as_deref()) {
+                        writeln!(f, "{}- {}", child, self.1.markdown_note(note))?;
+                    }
+                }
+                return Ok(());
+            }
+            for (idx, annotations) in group_by_book(annotations, self.1).into_iter().enumerate() {
+                if idx > 0 {
+                    write!(f, "{}", book_separator)?;
+                }
+                let title = format!("[[{}]]", annotations[0].book_title);
+                writeln!(f, "{}- {}", base, self.1.heading(&annotations, &title))?;
+                if let Some(entry) = self.1.bibtex(annotations[0], &mut cite_keys) {
+                    // Multiline block content is indented to the level of block text
+                    writeln!(f, "{}- ```bibtex", child)?;
+                    for line in entry.lines() {
+                        writeln!(f, "{}  {}", child, line)?;
+                    }
+                    writeln!(f, "{}  ```", child)?;
+                }
+                let groups = self.1.color_groups(annotations);
+                for (group_idx, (heading, annotations)) in groups.into_iter().enumerate() {
+                    if group_idx > 0 {
+                        write!(f, "{}", annotation_separator)?;
+                    }
+                    // Color sub-blocks nest annotations one level deeper
+                    let (child, grandchild) = match heading {
+                        Some(heading) => {
+                            writeln!(f, "{}- {}", child, heading)?;
+                            (format!("{}\t\t", child), format!("{}\t\t\t", child))
+                        }
+                        None => (child.clone(), grandchild.clone()),
+                    };
+                    for (idx, a) in annotations.into_iter().enumerate() {
+                        if idx > 0 {
+                            write!(f, "{}", annotation_separator)?;
+                        }
+                        let text = self.1.markdown_text(a);
+                        self.1.block(f, &child, |f| {
+                            let skip_quote = compact && is_empty(a);
+                            let note_above =
+                                self.1.note_position == NotePosition::Above || skip_quote;
+                            let indent = match &a.note {
+                                Some(note) if note_above => {
+                                    writeln!(f, "{}- {}", child, self.1.markdown_note(note))?;
+                                    &grandchild
+                                }
+                                _ => &child,
+                            };
+                            if skip_quote {
+                                return Ok(());
+                            }
+                            writeln!(f, "{}- > {}{}", indent, text, self.1.color_tag(a))?;
+                            if let Some(color) = a.color.filter(|_| self.1.logseq_color_property) {
+                                writeln!(f, "{}  color:: {}", indent, color.name())?;
+                            }
+                            if let Some(context) = self.1.context(a) {
+                                writeln!(f, "{}\t- {}", indent, context)?;
+                            }
+                            if self.1.with_open_command {
+                                writeln!(f, "{}\t- `{}`", indent, a.open_command())?;
+                            }
+                            match &a.note {
+                                Some(note) if !note_above => {
+                                    writeln!(f, "{}\t- {}", indent, self.1.markdown_note(note))
+                                }
+                                _ => Ok(()),
+                            }
+                        })?;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Mermaid timeline format
+    ///
+    /// Formatting annotations as a timeline of months with books highlighted in every month
+    /// ```text
+    /// timeline
+    ///     title Highlights
+    ///     2022-12 : Book 1 (2 highlights)
+    ///             : Book 2 (1 highlights)
+    /// ```
+    pub(crate) struct Mermaid<'a>(pub Vec<Annotation>, pub &'a Options);
+
+    impl fmt::Display for Mermaid<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let mut months = BTreeMap::<String, BTreeMap<&str, usize>>::new();
+            for a in &self.0 {
+                let month = self.1.timezone.convert(a.anotation_time).format("%Y-%m");
+                *months
+                    .entry(month.to_string())
+                    .or_default()
+                    .entry(&a.book_title)
+                    .or_default() += 1;
+            }
+
+            writeln!(f, "timeline")?;
+            writeln!(f, "    title Highlights")?;
+            for (month, books) in months {
+                for (idx, (book, count)) in books.into_iter().enumerate() {
+                    // Colon separates events in Mermaid timeline, so it can't be used in titles
+                    let book = book.replace(':', " -").replace('\n', " ");
+                    let period = if idx == 0 { month.as_str() } else { "" };
+                    writeln!(f, "    {:7} : {} ({} highlights)", period, book, count)?;
+                }
+            }
+            Ok(())
+        }
+    }
 
-    #[error("Unable to find program location")]
-    UnableToFindProgramLocation,
+    /// CSV format
+    ///
+    /// Fields are quoted according to RFC 4180, so multi-line highlights stay in a single record.
+    pub(crate) struct Csv<'a>(pub Vec<Annotation>, pub &'a Options);
 
-    #[error("Unable to write sync-file")]
-    UnableToWriteSyncFile,
+    impl fmt::Display for Csv<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            if !self.1.no_header {
+                write!(f, "book_title,author,time,color,selected_text,note")?;
+            }
+            for (idx, a) in self.0.iter().enumerate() {
+                let time = if self.1.epoch_timestamps {
+                    a.anotation_time.timestamp().to_string()
+                } else {
+                    a.anotation_time.to_rfc3339()
+                };
+                let fields = [
+                    a.book_title.as_str(),
+                    a.author.as_deref().unwrap_or_default(),
+                    &time,
+                    a.color.map(HighlightColor::name).unwrap_or_default(),
+                    self.1.text(a),
+                    a.note.as_deref().unwrap_or_default(),
+                ];
+                if idx > 0 || !self.1.no_header {
+                    writeln!(f)?;
+                }
+                for (idx, field) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_csv_field(f, field)?;
+                }
+            }
+            Ok(())
+        }
+    }
 
-    #[error("Unable to read sync-file")]
-    UnableToReadSyncFile,
-}
+    /// Notion import format
+    ///
+    /// CSV with columns named after Notion database properties. Title is the first column, so
+    /// it becomes the page name. Dates are ISO 8601, colors are capitalized (eg. `Yellow`) to be
+    /// imported as select options.
+    pub(crate) struct Notion<'a>(pub Vec<Annotation>, pub &'a Options);
 
-#[derive(Serialize)]
-struct Annotation {
-    selected_text: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    note: Option<String>,
-    anotation_time: DateTime<Utc>,
-    book_title: String,
-}
+    impl fmt::Display for Notion<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Title,Author,Highlight,Note,Date,Color")?;
+            for a in &self.0 {
+                let time = self.1.timezone.convert(a.anotation_time);
+                let color = a.color.map(HighlightColor::name).unwrap_or_default();
+                let mut chars = color.chars();
+                let color = match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => String::new(),
+                };
+                let fields = [
+                    a.book_title.as_str(),
+                    a.author.as_deref().unwrap_or_default(),
+                    self.1.text(a),
+                    a.note.as_deref().unwrap_or_default(),
+                    &time.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    &color,
+                ];
+                writeln!(f)?;
+                for (idx, field) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_csv_field(f, field)?;
+                }
+            }
+            Ok(())
+        }
+    }
 
-fn main() -> Result<()> {
-    env_logger::init();
-    let args = Args::parse();
+    fn write_csv_field(f: &mut fmt::Formatter<'_>, field: &str) -> fmt::Result {
+        if field.contains(['"', ',', '\n', '\r']) {
+            write!(f, "\"{}\"", field.replace('"', "\"\""))
+        } else {
+            write!(f, "{}", field)
+        }
+    }
 
-    let (annotation_db, library_db) = locate_annotation_database()?
-        .zip(locate_library_database()?)
-        .ok_or(Errors::NoDbFound)?;
+    /// HTML format
+    ///
+    /// Standalone page with a section per book, highlights are rendered as blockquotes.
+    pub(crate) struct Html<'a>(pub Vec<Annotation>, pub &'a Options);
 
-    debug!("Library database location: {:?}", &library_db);
-    debug!("Annotation database location: {:?}", &annotation_db);
+    impl fmt::Display for Html<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(f, "<!DOCTYPE html>")?;
+            writeln!(f, "<html>")?;
+            writeln!(f, "<head>")?;
+            writeln!(f, "<meta charset=\"utf-8\">")?;
+            writeln!(f, "<title>iBooks highlights</title>")?;
+            writeln!(f, "</head>")?;
+            writeln!(f, "<body>")?;
+            for annotations in group_by_book(&self.0, self.1) {
+                let book = annotations[0];
+                writeln!(f, "<section>")?;
+                if let Some(cover) = self.1.covers.get(&book.asset_id) {
+                    writeln!(
+                        f,
+                        "<img src=\"file://{}\" alt=\"{}\">",
+                        escape_html(&cover.to_string_lossy().replace(' ', "%20")),
+                        escape_html(&book.book_title)
+                    )?;
+                }
+                let heading = self.1.heading(&annotations, &book.book_title);
+                writeln!(f, "<h2>{}</h2>", escape_html(&heading))?;
+                if let Some(author) = &book.author {
+                    writeln!(f, "<p><em>{}</em></p>", escape_html(author))?;
+                }
+                for a in annotations {
+                    let text = escape_html(self.1.text(a)).replace('\n', "<br>\n");
+                    writeln!(
+                        f,
+                        "<blockquote id=\"{}\">{}</blockquote>",
+                        escape_html(&a.slug),
+                        text
+                    )?;
+                    if let Some(note) = &a.note {
+                        let note = escape_html(&self.1.note(note)).replace('\n', "<br>\n");
+                        writeln!(f, "<p>{}</p>", note)?;
+                    }
+                    writeln!(
+                        f,
+                        "<p><small><a href=\"{}\">{}</a></small></p>",
+                        escape_html(&a.url()),
+                        self.1.time(a)
+                    )?;
+                }
+                writeln!(f, "</section>")?;
+            }
+            writeln!(f, "</body>")?;
+            write!(f, "</html>")
+        }
+    }
 
-    let last_sync_file = LastSyncFile::find()?;
-    debug!("Last sync file: {:?}", last_sync_file.0);
+    /// Static site generator of [`Post`] format
+    #[derive(Clone, Copy)]
+    pub(crate) enum Site {
+        Hugo,
+        Jekyll,
+    }
 
-    let last_sync = if args.all {
-        None
-    } else {
-        last_sync_file.read()?
-    };
-    debug!("Last sync date: {:?}", last_sync);
+    /// Blog post format (Hugo or Jekyll)
+    ///
+    /// Every book is rendered as a markdown post with YAML front matter. The date of a post is
+    /// the time of the latest highlight
+    /// ```markdown
+    /// ---
+    /// title: "Book 1"
+    /// date: 2023-03-08T20:30:00+00:00
+    /// tags: ["highlights"]
+    /// draft: false
+    /// ---
+    ///
+    /// > annotation 1
+    /// ```
+    pub(crate) struct Post<'a>(pub Vec<Annotation>, pub &'a Options, pub Site);
 
-    let annotations = read_annotations(annotation_db, library_db, last_sync)?;
-    let new_last_sync_time = annotations.iter().map(|a| a.anotation_time).max();
+    impl fmt::Display for Post<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            // JSON strings are valid YAML scalars, so serde_json takes care of escaping
+            let quote = |s: &str| serde_json::to_string(s).unwrap_or_default();
+            let tags = self
+                .1
+                .post_tags
+                .iter()
+                .map(|t| quote(t))
+                .collect::<Vec<_>>();
+            for (idx, annotations) in group_by_book(&self.0, self.1).into_iter().enumerate() {
+                if idx > 0 {
+                    writeln!(f)?;
+                }
+                let book = annotations[0];
+                let latest = annotations.iter().map(|a| a.anotation_time).max();
+                let date = self.1.timezone.convert(latest.unwrap_or_default());
+                writeln!(f, "---")?;
+                if let Site::Jekyll = self.2 {
+                    writeln!(f, "layout: post")?;
+                }
+                let heading = self.1.heading(&annotations, &book.book_title);
+                writeln!(f, "title: {}", quote(&heading))?;
+                match self.2 {
+                    Site::Hugo => writeln!(
+                        f,
+                        "date: {}",
+                        date.to_rfc3339_opts(SecondsFormat::Secs, false)
+                    )?,
+                    Site::Jekyll => writeln!(f, "date: {}", date.format("%Y-%m-%d %H:%M:%S %z"))?,
+                }
+                if let Some(author) = &book.author {
+                    writeln!(f, "author: {}", quote(author))?;
+                }
+                writeln!(f, "tags: [{}]", tags.join(", "))?;
+                match self.2 {
+                    Site::Hugo => writeln!(f, "draft: false")?,
+                    Site::Jekyll => writeln!(f, "published: true")?,
+                }
+                writeln!(f, "---")?;
+                for a in annotations {
+                    writeln!(f)?;
+                    for line in self.1.markdown_text(a).lines() {
+                        writeln!(f, "> {}", line)?;
+                    }
+                    if let Some(note) = &a.note {
+                        writeln!(f)?;
+                        writeln!(f, "{}", self.1.markdown_note(note))?;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
 
-    if args.json {
-        println!("{}", format::Json(annotations));
-    } else if args.table {
-        println!("{}", format::Table(annotations));
-    } else if args.obsidian {
-        println!("{}", format::Obsidian(annotations));
-    } else {
-        println!("{}", format::Logseq(annotations));
+    /// Escapes characters special to BibTeX (and LaTeX) in field values
+    fn escape_bibtex(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '{' | '}' | '&' | '%' | '$' | '#' | '_' => escaped.push('\\'),
+                _ => {}
+            }
+            escaped.push(c);
+        }
+        escaped
     }
 
-    if args.update {
-        if let Some(time) = new_last_sync_time {
-            debug!("Updating last sync time: {}", time);
-            last_sync_file.update(time)?;
+    /// Escapes characters which start Markdown formatting, links and tags with backslash
+    fn escape_markdown(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            if matches!(c, '\\' | '`' | '*' | '_' | '#' | '[' | ']') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
         }
+        escaped
     }
 
-    Ok(())
-}
+    fn escape_html(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
 
-fn read_annotations(
-    annotation_db: impl AsRef<Path>,
-    library_db: impl AsRef<Path>,
-    created_after: Option<DateTime<Utc>>,
-) -> Result<Vec<Annotation>> {
-    let connection = Connection::open(annotation_db)?;
-    connection.execute("ATTACH DATABASE ? AS l", [library_db.as_ref().to_str()])?;
+    pub(crate) struct Table<'a>(pub Vec<Annotation>, pub &'a Options);
 
-    // Here I'm using ZFUTUREPROOFING6 instead of ZANNOTATIONMODIFICATIONDATE beacuse
-    // latter works unreliably. It seems like ZFUTUREPROOFING6 is annotation created time.
-    // At least I've found one other project using it:
-    // https://github.com/jay1803/ibook-server/blob/58838a3a1004aeaaa7cd7ebf4ef95edf8cc45ed3/controllers/bookController.js#L124
-    let mut stms = connection.prepare(
-        "select
-            a.ZANNOTATIONSELECTEDTEXT,
-            a.ZANNOTATIONNOTE,
-            round(a.ZFUTUREPROOFING6),
-            l.ZTITLE
-         from ZAEANNOTATION a
-         inner join ZBKLIBRARYASSET l ON l.ZASSETID = a.ZANNOTATIONASSETID
-         where a.ZANNOTATIONSELECTEDTEXT IS NOT NULL AND (a.ZANNOTATIONNOTE != '' OR a.ZANNOTATIONNOTE IS NULL) AND
-         round(a.ZFUTUREPROOFING6) > ?
-         ORDER BY a.ZFUTUREPROOFING6",
-    )?;
-    let created_after = created_after.map(|t| t.timestamp()).unwrap_or(0);
-    let annotations = stms.query_map([timestamp_to_core_data(created_after)], |row| {
-        let ts: f64 = row.get(2)?;
-        Ok(Annotation {
-            selected_text: row.get(0)?,
-            note: row.get(1)?,
-            anotation_time: core_data_to_timestamp(ts as i64),
-            book_title: row.get(3)?,
-        })
-    })?;
+    /// Minimal width of the highlight column of the table
+    const MIN_TEXT_COLUMN_WIDTH: usize = 20;
 
-    annotations
-        .map(|r| r.context(Errors::ContextProcessingAnnotation))
-        .collect::<Result<Vec<_>>>()
-}
+    impl fmt::Display for Table<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let mut table = term_table::Table::new();
 
-struct LastSyncFile(PathBuf);
+            let width = term_size::dimensions().map(|(w, _)| w).unwrap_or(120);
 
-impl LastSyncFile {
-    fn find() -> Result<Self> {
-        let mut state_dir = dirs::data_dir().ok_or(Errors::UnableToFindProgramLocation)?;
+            let show_lengths = self.1.show_lengths;
+            let columns = if show_lengths { 6 } else { 4 };
+            let column_separators = columns + 1;
+            let date_column_width = 19 + 2; // 19 characters of the date plus 2 padding chars
+            let type_column_width = 9 + 2; // "underline"
+            let length_column_width = 6 + 2;
+            let length_columns_width = if show_lengths {
+                2 * length_column_width
+            } else {
+                0
+            };
+            let title_column_width = (width.saturating_sub(date_column_width) / 4).max(10);
+            // On narrow terminals the table overflows, so the highlight column is still readable
+            let text_coulmn_width = width
+                .saturating_sub(
+                    date_column_width
+                        + type_column_width
+                        + title_column_width
+                        + length_columns_width
+                        + column_separators,
+                )
+                .max(MIN_TEXT_COLUMN_WIDTH);
+            table.set_max_column_widths(vec![
+                (0, title_column_width),
+                (1, date_column_width),
+                (2, type_column_width),
+                (3, text_coulmn_width),
+                (4, length_column_width),
+                (5, length_column_width),
+            ]);
+            table.style = TableStyle::rounded();
 
-        state_dir.push("ibooks-export");
-        if !state_dir.exists() {
-            fs::create_dir_all(&state_dir)?;
+            // Grouping by book first, so highlights of the same book are adjacent and can be
+            // counted
+            for annotations in group_by_book(&self.0, self.1) {
+                for annotation in &annotations {
+                    let mut text = self.1.table_text(annotation);
+                    if annotation.favorited == Some(true) {
+                        text = format!("★ {}", text);
+                    }
+                    let mut cells = vec![
+                        (annotation.book_title.clone(), title_column_width),
+                        (self.1.time(annotation), date_column_width),
+                        (annotation.annotation_type.clone(), type_column_width),
+                        (text, text_coulmn_width),
+                    ];
+                    if show_lengths {
+                        let note = annotation.note.as_deref().unwrap_or_default();
+                        cells.extend([
+                            (
+                                annotation.selected_text.chars().count().to_string(),
+                                length_column_width,
+                            ),
+                            (note.chars().count().to_string(), length_column_width),
+                        ]);
+                    }
+                    let color = annotation.color.filter(|_| self.1.color_rows);
+                    let row = Row::new(cells.into_iter().map(|(text, width)| match color {
+                        Some(color) => TableCell::new(tint(&text, width, color)),
+                        None => TableCell::new(text),
+                    }));
+                    table.add_row(row);
+                }
+                table.add_row(summary_row(
+                    format!(
+                        "{}: {} highlights",
+                        annotations[0].book_title,
+                        annotations.len()
+                    ),
+                    columns,
+                ));
+            }
+            if !self.0.is_empty() {
+                let total = format!("Total: {} highlights", self.0.len());
+                table.add_row(summary_row(total, columns));
+            }
+            write!(f, "{}", table.render())?;
+            Ok(())
         }
+    }
 
-        state_dir.push("last_sync");
+    /// vCard 3.0 format for contact and CRM import
+    ///
+    /// Every book is a card named after the book author (or the title if author is unknown)
+    /// with the title, highlights and notes in the `NOTE` field. Lines end with CRLF and are
+    /// folded at [`VCARD_LINE_LENGTH`] bytes
+    /// ```text
+    /// BEGIN:VCARD
+    /// VERSION:3.0
+    /// FN:Author 1
+    /// N:1;Author;;;
+    /// NOTE:Book 1\n\nannotation 1\n\nannotation 2\nnote
+    /// END:VCARD
+    /// ```
+    pub(crate) struct Vcard<'a>(pub Vec<Annotation>, pub &'a Options);
 
-        Ok(Self(state_dir))
-    }
+    const VCARD_LINE_LENGTH: usize = 75;
 
-    pub fn read(&self) -> Result<Option<DateTime<Utc>>> {
-        if !Path::new(&self.0).exists() {
-            return Ok(None);
+    impl fmt::Display for Vcard<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for annotations in group_by_book(&self.0, self.1) {
+                let book = annotations[0];
+                let name = book.author.as_deref().unwrap_or(&book.book_title);
+                // Structured name is family name followed by given names, titles are used as is
+                let (given, family) = match &book.author {
+                    Some(author) => author.trim().rsplit_once(' ').unwrap_or(("", author)),
+                    None => ("", name),
+                };
+                let mut note = vec![book.book_title.clone()];
+                for a in annotations {
+                    match &a.note {
+                        Some(n) => note.push(format!("{}\n{}", self.1.text(a), self.1.note(n))),
+                        None => note.push(self.1.text(a).to_string()),
+                    }
+                }
+                write!(f, "BEGIN:VCARD\r\n")?;
+                write!(f, "VERSION:3.0\r\n")?;
+                write_vcard_line(f, &format!("FN:{}", escape_vcard(name)))?;
+                let (family, given) = (escape_vcard(family), escape_vcard(given));
+                write_vcard_line(f, &format!("N:{};{};;;", family, given))?;
+                write_vcard_line(f, &format!("NOTE:{}", escape_vcard(&note.join("\n\n"))))?;
+                write!(f, "END:VCARD\r\n")?;
+            }
+            Ok(())
         }
+    }
 
-        let data = fs::read(&self.0).context(Errors::UnableToReadSyncFile)?;
+    /// iCalendar format
+    ///
+    /// Reading log with an all-day event for every highlight on the day it was created (in
+    /// `--tz` timezone). Event summary is the book title, description is the quote and the
+    /// note. Lines are written like [`Vcard`] ones
+    /// ```text
+    /// BEGIN:VCALENDAR
+    /// VERSION:2.0
+    /// PRODID:-//ibooks-export//EN
+    /// BEGIN:VEVENT
+    /// UID:h-1@ibooks-export
+    /// DTSTAMP:20221224T035640Z
+    /// DTSTART;VALUE=DATE:20221224
+    /// DTEND;VALUE=DATE:20221225
+    /// SUMMARY:Book 1
+    /// DESCRIPTION:annotation 1
+    /// END:VEVENT
+    /// END:VCALENDAR
+    /// ```
+    pub(crate) struct Ics<'a>(pub Vec<Annotation>, pub &'a Options);
 
-        let string = String::from_utf8(data)?;
-        let date = DateTime::parse_from_rfc3339(&string)?.with_timezone(&Utc);
-        Ok(Some(date))
+    impl fmt::Display for Ics<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "BEGIN:VCALENDAR\r\n")?;
+            write!(f, "VERSION:2.0\r\n")?;
+            write!(f, "PRODID:-//ibooks-export//EN\r\n")?;
+            let stamp = self.1.now.format("%Y%m%dT%H%M%SZ");
+            for a in &self.0 {
+                let day = self.1.timezone.convert(a.anotation_time).date_naive();
+                let next_day = day.succ_opt().unwrap_or(day);
+                let description = match &a.note {
+                    Some(note) => format!("{}\n\n{}", self.1.text(a), self.1.note(note)),
+                    None => self.1.text(a).to_string(),
+                };
+                write!(f, "BEGIN:VEVENT\r\n")?;
+                write_vcard_line(f, &format!("UID:{}@ibooks-export", a.slug))?;
+                write!(f, "DTSTAMP:{}\r\n", stamp)?;
+                write!(f, "DTSTART;VALUE=DATE:{}\r\n", day.format("%Y%m%d"))?;
+                write!(f, "DTEND;VALUE=DATE:{}\r\n", next_day.format("%Y%m%d"))?;
+                write_vcard_line(f, &format!("SUMMARY:{}", escape_vcard(&a.book_title)))?;
+                write_vcard_line(f, &format!("DESCRIPTION:{}", escape_vcard(&description)))?;
+                write!(f, "END:VEVENT\r\n")?;
+            }
+            write!(f, "END:VCALENDAR\r\n")
+        }
     }
 
-    pub fn update(&self, ts: DateTime<Utc>) -> Result<()> {
-        fs::write(&self.0, ts.to_rfc3339()).context(Errors::UnableToWriteSyncFile)
+    /// Escapes vCard (and iCalendar) property value, newlines are written as `\n`
+    fn escape_vcard(text: &str) -> String {
+        text.replace('\\', "\\\\")
+            .replace(',', "\\,")
+            .replace(';', "\\;")
+            .replace("\r\n", "\n")
+            .replace('\n', "\\n")
     }
-}
-
-fn locate_annotation_database() -> Result<Option<PathBuf>> {
-    locate_database("Library/Containers/com.apple.iBooksX/Data/Documents/AEAnnotation")
-}
 
-fn locate_library_database() -> Result<Option<PathBuf>> {
-    locate_database("Library/Containers/com.apple.iBooksX/Data/Documents/BKLibrary")
-}
-
-fn locate_database(path: impl AsRef<Path>) -> Result<Option<PathBuf>> {
-    let mut dir = dirs::home_dir().ok_or(Errors::NoHomeDir)?;
-    dir.push(path);
-
-    for file in fs::read_dir(dir)? {
-        let path = file?.path();
-        let extension = path.extension().and_then(OsStr::to_str).unwrap_or("");
-        if extension == "sqlite" {
-            return Ok(Some(path));
+    /// Writes vCard content line, folding it into lines not longer than [`VCARD_LINE_LENGTH`]
+    /// bytes. Continuation lines start with a space
+    fn write_vcard_line(f: &mut fmt::Formatter<'_>, line: &str) -> fmt::Result {
+        let mut length = 0;
+        for c in line.chars() {
+            if length + c.len_utf8() > VCARD_LINE_LENGTH {
+                write!(f, "\r\n ")?;
+                length = 1;
+            }
+            write!(f, "{}", c)?;
+            length += c.len_utf8();
         }
+        write!(f, "\r\n")
     }
 
-    Ok(None)
-}
-
-fn core_data_to_timestamp(ts: i64) -> DateTime<Utc> {
-    Utc.timestamp_opt(ts + 978307200, 0)
-        .single()
-        .expect("Invalid date")
-}
-
-fn timestamp_to_core_data(ts: i64) -> i64 {
-    ts - 978307200
-}
-
-mod format {
-    use super::*;
-    use chrono::Local;
-    use std::fmt;
-    use term_table::{row::Row, table_cell::TableCell, TableStyle};
+    /// GraphViz DOT format
+    ///
+    /// Every book is a node connected to nodes of its highlights, highlights are connected to
+    /// their notes. Labels are cut to [`DOT_LABEL_LENGTH`] characters
+    /// ```text
+    /// digraph highlights {
+    ///     b0 [label="Book 1", shape=box];
+    ///     h0 [label="annotation 1"];
+    ///     b0 -> h0;
+    /// }
+    /// ```
+    pub(crate) struct Dot<'a>(pub Vec<Annotation>, pub &'a Options);
 
-    /// Json format for annotations
-    pub(crate) struct Json(pub Vec<Annotation>);
+    const DOT_LABEL_LENGTH: usize = 40;
 
-    impl fmt::Display for Json {
+    impl fmt::Display for Dot<'_> {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            match serde_json::to_string(&self.0) {
-                Ok(json) => {
-                    write!(f, "{}", json)?;
-                    Ok(())
-                }
-                Err(e) => {
-                    error!("Unable to format error: {}", e);
-                    Err(fmt::Error)
+            writeln!(f, "digraph highlights {{")?;
+            writeln!(f, "    rankdir=LR;")?;
+            let mut highlight = 0;
+            for (book, annotations) in group_by_book(&self.0, self.1).into_iter().enumerate() {
+                let title = dot_label(&annotations[0].book_title);
+                writeln!(f, "    b{} [label=\"{}\", shape=box];", book, title)?;
+                for a in annotations {
+                    let text = dot_label(self.1.text(a));
+                    writeln!(f, "    h{} [label=\"{}\"];", highlight, text)?;
+                    writeln!(f, "    b{} -> h{};", book, highlight)?;
+                    if let Some(note) = &a.note {
+                        let note = dot_label(note);
+                        writeln!(f, "    n{} [label=\"{}\", shape=note];", highlight, note)?;
+                        writeln!(f, "    h{} -> n{};", highlight, highlight)?;
+                    }
+                    highlight += 1;
                 }
             }
+            write!(f, "}}")
         }
     }
 
-    /// Obsidian format
+    /// Label of a DOT node: whitespace is collapsed, long text is cut, quotes are escaped
+    fn dot_label(text: &str) -> String {
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        let text = if text.chars().count() > DOT_LABEL_LENGTH {
+            let cut = text.chars().take(DOT_LABEL_LENGTH - 1).collect::<String>();
+            format!("{}…", cut.trim_end())
+        } else {
+            text
+        };
+        text.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
+    /// Notes format
     ///
-    /// Formatting annotations in Obsidian markdown format like
+    /// Notes of every book joined into a markdown document, in order of their location in the
+    /// book. Highlights are cited after notes with `--notes-with-quotes`
     /// ```markdown
-    /// [[Book Title 1]]
+    /// # Book 1
     ///
-    /// Note text (if any)
-    /// > Annotation text
-    /// ---
-    /// [[Book Title 2]]
+    /// note 1
     ///
-    /// Note text (if any)
-    /// > Annotation text
+    /// note 2
     /// ```
-    pub(crate) struct Obsidian(pub Vec<Annotation>);
+    pub(crate) struct Notes<'a>(pub Vec<Annotation>, pub &'a Options);
 
-    impl fmt::Display for Obsidian {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            let mut annotations_by_book = HashMap::new();
-            let annotations = &self.0;
-            for a in annotations {
-                annotations_by_book
-                    .entry(a.book_title.clone())
-                    .or_insert_with(Vec::new)
-                    .push(a);
-            }
+    /// Annotation has a note which is not blank
+    pub(crate) fn has_note(a: &Annotation) -> bool {
+        a.note.as_deref().is_some_and(|n| !n.trim().is_empty())
+    }
 
-            for (idx, (book, annotations)) in annotations_by_book.iter().enumerate() {
+    impl fmt::Display for Notes<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let annotations = self.0.iter().filter(|a| has_note(a));
+            for (idx, mut annotations) in group_by_book(annotations, self.1).into_iter().enumerate()
+            {
                 if idx > 0 {
-                    writeln!(f, "---")?;
                     writeln!(f)?;
                 }
-                writeln!(f, "[[{}]]", book)?;
-                writeln!(f)?;
+                // Stable sort, annotations without location keep their time order
+                annotations.sort_by_key(|a| a.location.as_deref().map(cfi_sort_key));
+                let title = &annotations[0].book_title;
+                write!(f, "# {}", self.1.heading(&annotations, title))?;
                 for a in annotations {
-                    let text = &a.selected_text;
-                    if let Some(note) = &a.note {
-                        writeln!(f, "{}", note)?;
-                    }
-                    for line in text.lines() {
-                        writeln!(f, "> {}", line)?;
-                    }
                     writeln!(f)?;
+                    writeln!(f)?;
+                    write!(
+                        f,
+                        "{}",
+                        self.1.markdown_note(a.note.as_deref().unwrap_or_default())
+                    )?;
+                    if self.1.notes_with_quotes && !a.selected_text.trim().is_empty() {
+                        writeln!(f)?;
+                        writeln!(f)?;
+                        let quote = self.1.markdown_text(a);
+                        write!(
+                            f,
+                            "> <small>{}</small>",
+                            quote.lines().collect::<Vec<_>>().join(" ")
+                        )?;
+                    }
                 }
             }
             Ok(())
         }
     }
 
-    /// Logseq format
+    /// Tags format
     ///
-    /// Formatting annotations in logseq format like
+    /// Annotations grouped under `#hashtags` found in their notes, alphabetically. Annotation with
+    /// several tags is repeated under each of them, annotations without tags go last
     /// ```markdown
-    /// - [[Book 1]]
-    ///     - > annotation 1
-    ///     - > annotation 2
-    /// - [[Book 2]]
-    ///     - > annotation 1
+    /// # #idea
+    ///
+    /// > highlight
+    ///
+    /// note #idea
+    ///
+    /// — Book 1
+    ///
+    /// # untagged
     /// ```
-    pub(crate) struct Logseq(pub Vec<Annotation>);
+    pub(crate) struct Tags<'a>(pub Vec<Annotation>, pub &'a Options);
 
-    impl fmt::Display for Logseq {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            let mut annotations_by_book = HashMap::new();
-            let annotations = &self.0;
-            for a in annotations {
-                annotations_by_book
-                    .entry(a.book_title.clone())
-                    .or_insert_with(Vec::new)
-                    .push(a);
+    /// Lowercase hashtags of a note without `#`, eg. `idea` for "My note #Idea."
+    pub(crate) fn note_tags(note: &str) -> Vec<String> {
+        let mut tags = vec![];
+        for word in note.split_whitespace() {
+            let Some(tag) = word.strip_prefix('#') else {
+                continue;
+            };
+            let tag = tag
+                .split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_' || c == '/'))
+                .next()
+                .unwrap_or_default()
+                .to_lowercase();
+            if !tag.is_empty() && !tags.contains(&tag) {
+                tags.push(tag);
             }
+        }
+        tags
+    }
 
-            for (book, annotations) in annotations_by_book {
-                writeln!(f, "- [[{}]]", book)?;
+    impl fmt::Display for Tags<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let mut tags = BTreeMap::<_, Vec<_>>::new();
+            let mut untagged = vec![];
+            for a in &self.0 {
+                let note_tags = note_tags(a.note.as_deref().unwrap_or_default());
+                if note_tags.is_empty() {
+                    untagged.push(a);
+                }
+                for tag in note_tags {
+                    tags.entry(format!("#{}", tag)).or_default().push(a);
+                }
+            }
+            let untagged = (!untagged.is_empty()).then(|| ("untagged".to_string(), untagged));
+            for (idx, (tag, annotations)) in tags.into_iter().chain(untagged).enumerate() {
+                if idx > 0 {
+                    writeln!(f)?;
+                    writeln!(f)?;
+                }
+                write!(f, "# {}", tag)?;
                 for a in annotations {
+                    writeln!(f)?;
+                    writeln!(f)?;
+                    if !a.selected_text.trim().is_empty() {
+                        for line in self.1.markdown_text(a).lines() {
+                            writeln!(f, "> {}", line)?;
+                        }
+                        writeln!(f)?;
+                    }
                     if let Some(note) = &a.note {
-                        writeln!(f, "\t\t- {}", note)?;
-                        writeln!(f, "\t\t\t- > {}", a.selected_text)?;
-                    } else {
-                        writeln!(f, "\t\t- > {}", a.selected_text)?;
+                        writeln!(f, "{}", self.1.markdown_note(note))?;
+                        writeln!(f)?;
                     }
+                    write!(f, "— {}", a.book_title)?;
                 }
             }
             Ok(())
         }
     }
 
-    pub(crate) struct Table(pub Vec<Annotation>);
-
-    impl fmt::Display for Table {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            let mut table = term_table::Table::new();
-
-            let width = term_size::dimensions().map(|(w, _)| w).unwrap_or(120);
+    /// Preview format
+    ///
+    /// One line per annotation with book title and up to [`PREVIEW_LENGTH`] first characters
+    /// of the highlight (whitespace is collapsed)
+    /// ```text
+    /// Book 1: annotation 1
+    /// Book 2: very long annotation is cut…
+    /// ```
+    pub(crate) struct Preview<'a>(pub Vec<Annotation>, pub &'a Options);
 
-            let column_separators = 4;
-            let date_column_width = 19 + 2; // 19 characters of the date plus 2 padding chars
-            let title_column_width = ((width - date_column_width) / 4).max(10);
-            let text_coulmn_width =
-                width - date_column_width - title_column_width - column_separators;
-            table.set_max_column_widths(vec![
-                (0, title_column_width),
-                (1, date_column_width),
-                (2, text_coulmn_width),
-            ]);
-            table.style = TableStyle::rounded();
+    const PREVIEW_LENGTH: usize = 80;
 
-            for annotation in &self.0 {
-                let time = annotation
-                    .anotation_time
-                    .with_timezone(&Local)
-                    .naive_local();
-                let row = Row::new(vec![
-                    TableCell::new(&annotation.book_title),
-                    TableCell::new(time),
-                    TableCell::new(&annotation.selected_text),
-                ]);
-                table.add_row(row);
+    impl fmt::Display for Preview<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for (idx, a) in self.0.iter().enumerate() {
+                if idx > 0 {
+                    writeln!(f)?;
+                }
+                let text = self
+                    .1
+                    .text(a)
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                write!(f, "{}: ", a.book_title)?;
+                if let Some(n) = a.number {
+                    write!(f, "[{}] ", n)?;
+                }
+                if text.chars().count() > PREVIEW_LENGTH {
+                    let cut = text.chars().take(PREVIEW_LENGTH - 1).collect::<String>();
+                    write!(f, "{}…", cut.trim_end())?;
+                } else {
+                    write!(f, "{}", text)?;
+                }
             }
-            write!(f, "{}", table.render())?;
             Ok(())
         }
     }
+
+    /// Wraps text to the width of a table column and colors every line separately
+    ///
+    /// Coloring lines separately prevents color from leaking to table borders when
+    /// term-table wraps the cell content.
+    fn tint(text: &str, column_width: usize, color: HighlightColor) -> String {
+        let code = match color {
+            HighlightColor::Green => "32",
+            HighlightColor::Blue => "34",
+            HighlightColor::Yellow => "33",
+            HighlightColor::Pink => "95",
+            HighlightColor::Purple => "35",
+        };
+        // Column width includes one padding character on each side
+        let width = column_width.saturating_sub(2).max(1);
+        let mut lines = vec![];
+        for line in text.lines() {
+            let mut buf = String::new();
+            for c in line.chars() {
+                if string_width(&buf) >= width {
+                    lines.push(std::mem::take(&mut buf));
+                }
+                buf.push(c);
+            }
+            lines.push(buf);
+        }
+        lines
+            .iter()
+            .map(|line| format!("\x1b[{}m{}\x1b[0m", code, line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Row spanning given number of columns (all columns of the table) with right aligned text
+    fn summary_row<'a>(text: String, columns: usize) -> Row<'a> {
+        Row::new(vec![TableCell::new_with_alignment(
+            text,
+            columns,
+            Alignment::Right,
+        )])
+    }
 }
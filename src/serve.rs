@@ -0,0 +1,222 @@
+//! Minimal HTTP server exposing annotations as JSON (`ibooks-export serve`)
+//!
+//! Annotations are read from the databases on every request, so the response is always up to
+//! date. Only `GET /annotations` is served, with optional query parameters:
+//! - `book` – part of the book title (same as `--book`), can be repeated
+//! - `since` – only annotations created after given date (YYYY-MM-DD or RFC3339)
+//!
+//! Requests are handled one at a time and the server listens on the loopback interface only.
+//! Clients not sending the request within [`READ_TIMEOUT`] or sending too long headers get an
+//! error, so they can't block the server.
+use super::{
+    databases, format, parse_date, percent_decode, read_annotations, AnnotationQuery, Args,
+    BookFilter, Errors, OutputFormat,
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, error};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Time given to a client to send the request line and headers
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum size of the request line and headers together
+const MAX_HEADER_BYTES: u64 = 16 * 1024;
+
+/// Maximum number of header lines
+const MAX_HEADER_LINES: usize = 100;
+
+pub(crate) struct Response {
+    pub(crate) status: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn error(status: &'static str, message: impl ToString) -> Self {
+        let body = serde_json::json!({ "error": message.to_string() }).to_string();
+        Self { status, body }
+    }
+}
+
+/// Serves annotations on given port until the process is killed
+pub(crate) fn run(args: &Args, port: u16) -> Result<()> {
+    let (annotation_dbs, library_db) = databases(args)?;
+    let mut annotation_dbs = annotation_dbs.into_iter();
+    let (annotation_db, library_db) = annotation_dbs
+        .next()
+        .zip(library_db)
+        .ok_or(Errors::NoDbFound)?;
+    let secondary_dbs = annotation_dbs.collect::<Vec<_>>();
+    let options = format::Options {
+        timezone: args.timezone(OutputFormat::Json)?,
+        ..format::Options::default()
+    };
+    let server = Server {
+        args,
+        annotation_db: &annotation_db,
+        secondary_dbs: &secondary_dbs,
+        library_db: &library_db,
+        options: &options,
+    };
+
+    let listener =
+        TcpListener::bind((Ipv4Addr::LOCALHOST, port)).context(Errors::UnableToListen(port))?;
+    eprintln!(
+        "Serving annotations at http://127.0.0.1:{}/annotations",
+        port
+    );
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Unable to accept connection: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = server.handle(stream) {
+            error!("Unable to handle request: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Reads request line and skips headers
+///
+/// Headers are not used, but have to be read before the response is written. Error response is
+/// returned if the client is too slow or headers exceed the limits.
+pub(crate) fn read_request_line(reader: impl BufRead) -> Result<String, Response> {
+    let mut reader = reader.take(MAX_HEADER_BYTES);
+    let mut request_line = String::new();
+    for lines in 0.. {
+        if lines > MAX_HEADER_LINES {
+            return Err(too_large());
+        }
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            // Client closed the connection without finishing headers
+            Ok(0) => break,
+            Ok(_) if !line.ends_with('\n') && reader.limit() == 0 => return Err(too_large()),
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(Response::error(
+                    "408 Request Timeout",
+                    "Request not received",
+                ))
+            }
+            Err(_) => return Err(Response::error("400 Bad Request", "Malformed request")),
+        }
+        if lines == 0 {
+            request_line = line;
+        } else if line.trim().is_empty() {
+            break;
+        }
+    }
+    Ok(request_line)
+}
+
+fn too_large() -> Response {
+    Response::error(
+        "431 Request Header Fields Too Large",
+        "Headers are too large",
+    )
+}
+
+struct Server<'a> {
+    args: &'a Args,
+    annotation_db: &'a PathBuf,
+    secondary_dbs: &'a [PathBuf],
+    library_db: &'a PathBuf,
+    options: &'a format::Options,
+}
+
+impl Server<'_> {
+    fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let response = match read_request_line(BufReader::new(&stream)) {
+            Ok(request_line) => {
+                debug!("Request: {}", request_line.trim());
+                match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["GET", target, _] => self.respond(target),
+                    [_, _, _] => Response::error("405 Method Not Allowed", "Only GET is supported"),
+                    _ => Response::error("400 Bad Request", "Malformed request"),
+                }
+            }
+            Err(response) => response,
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            response.status,
+            response.body.len(),
+            response.body
+        )?;
+        Ok(())
+    }
+
+    fn respond(&self, target: &str) -> Response {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        if path != "/annotations" {
+            return Response::error("404 Not Found", format!("No such path: {}", path));
+        }
+        let mut books = vec![];
+        let mut since = None;
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            let Some(value) = percent_decode(&value.replace('+', " ")) else {
+                return Response::error("400 Bad Request", format!("Malformed value of {}", name));
+            };
+            match name {
+                "book" => books.push(value),
+                "since" => match parse_date(&value) {
+                    Ok(date) => since = Some(date),
+                    Err(e) => return Response::error("400 Bad Request", e),
+                },
+                _ => {
+                    return Response::error(
+                        "400 Bad Request",
+                        format!("Unknown parameter: {}", name),
+                    )
+                }
+            }
+        }
+        match self.annotations(&books, since) {
+            Ok(body) => Response {
+                status: "200 OK",
+                body,
+            },
+            Err(e) => {
+                error!("Unable to read annotations: {:?}", e);
+                Response::error("500 Internal Server Error", e)
+            }
+        }
+    }
+
+    fn annotations(&self, books: &[String], since: Option<DateTime<Utc>>) -> Result<String> {
+        let query = AnnotationQuery {
+            created_after: since,
+            include_deleted: self.args.include_deleted,
+            timeout: self.args.timeout,
+            skip_errors: self.args.skip_errors,
+            ..AnnotationQuery::default()
+        };
+        let mut annotations = read_annotations(
+            self.annotation_db,
+            self.secondary_dbs,
+            self.library_db,
+            &query,
+        )?;
+        let book_filter = BookFilter::new(books, &[], &[], &[]);
+        annotations.retain(|a| book_filter.matches(&a.book_title, &a.asset_id));
+        Ok(format::Json(annotations, self.options).try_to_string()?)
+    }
+}
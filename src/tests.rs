@@ -0,0 +1,693 @@
+//! Tests reading annotations from fixture iBooks databases
+//!
+//! Fixture databases are created in a temporary directory with a minimal version of iBooks
+//! schema (only the columns export depends on).
+use super::*;
+
+/// Annotation and library databases created in a temporary directory, removed on drop
+struct Fixture {
+    dir: PathBuf,
+}
+
+const ANNOTATION_SCHEMA: &str = "CREATE TABLE ZAEANNOTATION (
+    Z_PK INTEGER PRIMARY KEY,
+    ZANNOTATIONASSETID VARCHAR,
+    ZANNOTATIONSELECTEDTEXT VARCHAR,
+    ZANNOTATIONNOTE VARCHAR,
+    ZFUTUREPROOFING6 FLOAT,
+    ZANNOTATIONCREATIONDATE TIMESTAMP,
+    ZANNOTATIONMODIFICATIONDATE TIMESTAMP,
+    ZANNOTATIONDELETED INTEGER,
+    ZANNOTATIONSTYLE INTEGER,
+    ZANNOTATIONUUID VARCHAR,
+    ZANNOTATIONLOCATION VARCHAR,
+    ZANNOTATIONREPRESENTATIVETEXT VARCHAR
+)";
+
+const LIBRARY_SCHEMA: &str = "CREATE TABLE ZBKLIBRARYASSET (
+    Z_PK INTEGER PRIMARY KEY,
+    ZASSETID VARCHAR,
+    ZTITLE VARCHAR,
+    ZAUTHOR VARCHAR
+)";
+
+/// Core Data timestamp of 2023-03-08 20:26:40 UTC
+const TS: f64 = 700_000_000.0;
+
+impl Fixture {
+    /// Creates databases with two books and a handful of annotations
+    ///
+    /// `name` makes the directory unique, so tests can run in parallel.
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "ibooks-export-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let fixture = Self { dir };
+
+        let library = Connection::open(fixture.library_db()).unwrap();
+        library.execute(LIBRARY_SCHEMA, []).unwrap();
+        for (id, title, author) in [
+            ("A1", "Book One", Some("Alice Author")),
+            ("A2", "Book Two", None),
+        ] {
+            library
+                .execute(
+                    "INSERT INTO ZBKLIBRARYASSET (ZASSETID, ZTITLE, ZAUTHOR) VALUES (?, ?, ?)",
+                    params![id, title, author],
+                )
+                .unwrap();
+        }
+
+        let annotations = Connection::open(fixture.annotation_db()).unwrap();
+        annotations.execute(ANNOTATION_SCHEMA, []).unwrap();
+        fixture
+    }
+
+    fn annotation_db(&self) -> PathBuf {
+        self.dir.join("AEAnnotation.sqlite")
+    }
+
+    fn library_db(&self) -> PathBuf {
+        self.dir.join("BKLibrary.sqlite")
+    }
+
+    /// Adds an annotation created at `TS + offset`
+    fn insert(&self, asset_id: &str, text: Option<&str>, note: Option<&str>, offset: f64) {
+        let connection = Connection::open(self.annotation_db()).unwrap();
+        let uuid = format!("U{}", offset);
+        connection
+            .execute(
+                "INSERT INTO ZAEANNOTATION (
+                    ZANNOTATIONASSETID, ZANNOTATIONSELECTEDTEXT, ZANNOTATIONNOTE,
+                    ZFUTUREPROOFING6, ZANNOTATIONCREATIONDATE, ZANNOTATIONMODIFICATIONDATE,
+                    ZANNOTATIONDELETED, ZANNOTATIONSTYLE, ZANNOTATIONUUID, ZANNOTATIONLOCATION
+                ) VALUES (?, ?, ?, ?, ?, ?, 0, 3, ?, 'epubcfi(/6/4[ch1]!/4/2,/1:0,/1:5)')",
+                params![
+                    asset_id,
+                    text,
+                    note,
+                    TS + offset,
+                    TS + offset,
+                    TS + offset,
+                    uuid
+                ],
+            )
+            .unwrap();
+    }
+
+    /// Standard set of annotations: two highlights in the first book and one in the second
+    fn with_annotations(self) -> Self {
+        self.insert("A1", Some("First highlight"), None, 0.);
+        self.insert("A2", Some("Other book"), Some("A note"), 100.);
+        self.insert("A1", Some("Second highlight"), None, 200.5);
+        self
+    }
+
+    fn read(&self, query: &AnnotationQuery) -> Vec<Annotation> {
+        read_annotations(self.annotation_db(), &[], self.library_db(), query).unwrap()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn texts(annotations: &[Annotation]) -> Vec<&str> {
+    annotations
+        .iter()
+        .map(|a| a.selected_text.as_str())
+        .collect()
+}
+
+#[test]
+fn reads_annotations_ordered_by_creation_time() {
+    let fixture = Fixture::new("ordered").with_annotations();
+    let annotations = fixture.read(&AnnotationQuery::default());
+
+    assert_eq!(
+        texts(&annotations),
+        ["First highlight", "Other book", "Second highlight"]
+    );
+    let a = &annotations[0];
+    assert_eq!(a.book_title, "Book One");
+    assert_eq!(a.author.as_deref(), Some("Alice Author"));
+    assert_eq!(a.asset_id, "A1");
+    assert_eq!(a.color, Some(HighlightColor::Yellow));
+    assert_eq!(a.word_count, 2);
+    assert_eq!(
+        a.location_start.as_deref(),
+        Some("epubcfi(/6/4[ch1]!/4/2/1:0)")
+    );
+    assert_eq!(
+        a.location_end.as_deref(),
+        Some("epubcfi(/6/4[ch1]!/4/2/1:5)")
+    );
+    assert_eq!(annotations[1].note.as_deref(), Some("A note"));
+    assert_eq!(annotations[1].author, None);
+}
+
+#[test]
+fn converts_core_data_timestamps() {
+    let fixture = Fixture::new("timestamps").with_annotations();
+    let annotations = fixture.read(&AnnotationQuery::default());
+
+    assert_eq!(
+        annotations[0].anotation_time,
+        Utc.with_ymd_and_hms(2023, 3, 8, 20, 26, 40).unwrap()
+    );
+    assert_eq!(
+        annotations[2].anotation_time,
+        Utc.with_ymd_and_hms(2023, 3, 8, 20, 30, 0).unwrap() + Duration::milliseconds(500)
+    );
+    assert_eq!(
+        timestamp_to_core_data(annotations[2].anotation_time),
+        TS + 200.5
+    );
+}
+
+#[test]
+fn skips_annotations_without_text() {
+    let fixture = Fixture::new("empty").with_annotations();
+    fixture.insert("A1", None, None, 300.);
+    fixture.insert("A1", Some("  \n"), None, 400.);
+    fixture.insert("A1", Some(""), Some("Note only"), 500.);
+    let annotations = fixture.read(&AnnotationQuery::default());
+
+    assert_eq!(annotations.len(), 4);
+    assert_eq!(annotations[3].note.as_deref(), Some("Note only"));
+}
+
+#[test]
+fn skips_annotations_of_books_missing_in_library() {
+    let fixture = Fixture::new("orphans").with_annotations();
+    fixture.insert("A3", Some("Orphan"), None, 300.);
+    let annotations = fixture.read(&AnnotationQuery::default());
+
+    assert!(!texts(&annotations).contains(&"Orphan"));
+}
+
+#[test]
+fn filters_by_creation_time_and_book() {
+    let fixture = Fixture::new("filters").with_annotations();
+
+    let query = AnnotationQuery {
+        created_after: core_data_to_timestamp(TS + 50.),
+        ..Default::default()
+    };
+    assert_eq!(
+        texts(&fixture.read(&query)),
+        ["Other book", "Second highlight"]
+    );
+
+    let query = AnnotationQuery {
+        book_ids: vec!["A2".into()],
+        ..Default::default()
+    };
+    assert_eq!(texts(&fixture.read(&query)), ["Other book"]);
+}
+
+#[test]
+fn paginates_annotations() {
+    let fixture = Fixture::new("paginate").with_annotations();
+    let annotations = fixture.read(&AnnotationQuery::default());
+    let first = annotations[0].anotation_time;
+
+    let (page, first_dropped) = paginate(annotations, 1, Some(1));
+    assert_eq!(texts(&page), ["Other book"]);
+    assert_eq!(first_dropped, Some(first));
+}
+
+#[test]
+fn reads_bkannotation_layout() {
+    let fixture = Fixture::new("bkannotation").with_annotations();
+    let connection = Connection::open(fixture.annotation_db()).unwrap();
+    connection
+        .execute("ALTER TABLE ZAEANNOTATION RENAME TO ZBKANNOTATION", [])
+        .unwrap();
+    for column in ANNOTATION_COLUMNS {
+        let renamed = AnnotationLayout::BkAnnotation.column(column);
+        if renamed != *column {
+            let sql = format!("ALTER TABLE ZBKANNOTATION RENAME {} TO {}", column, renamed);
+            connection.execute(&sql, []).unwrap();
+        }
+    }
+    let annotations = fixture.read(&AnnotationQuery::default());
+
+    assert_eq!(
+        texts(&annotations),
+        ["First highlight", "Other book", "Second highlight"]
+    );
+    assert_eq!(annotations[1].note.as_deref(), Some("A note"));
+    assert_eq!(annotations[0].color, Some(HighlightColor::Yellow));
+}
+
+#[test]
+fn formats_annotations_as_logseq() {
+    let fixture = Fixture::new("logseq").with_annotations();
+    let annotations = fixture.read(&AnnotationQuery::default());
+    let options = format::Options::default();
+
+    assert_eq!(
+        format::Logseq(annotations, &options).to_string(),
+        "- [[Book One]]\n\
+         \t\t- > First highlight\n\
+         \t\t- > Second highlight\n\
+         - [[Book Two]]\n\
+         \t\t- A note\n\
+         \t\t\t- > Other book\n"
+    );
+}
+
+#[test]
+fn limits_annotations_per_book() {
+    let fixture = Fixture::new("per-book").with_annotations();
+    let annotations = fixture.read(&AnnotationQuery::default());
+
+    let (kept, dropped) = limit_per_book(annotations, 1);
+    assert_eq!(texts(&kept), ["First highlight", "Other book"]);
+    assert_eq!(texts(&dropped), ["Second highlight"]);
+}
+
+#[test]
+fn skips_annotations_with_out_of_range_timestamp() {
+    let fixture = Fixture::new("out-of-range").with_annotations();
+    fixture.insert("A2", Some("Bogus date"), None, 1e15);
+
+    let annotations = fixture.read(&AnnotationQuery::default());
+    assert_eq!(
+        texts(&annotations),
+        ["First highlight", "Other book", "Second highlight"]
+    );
+    // SQLite stores NaN as NULL, so it never comes from the database
+    assert_eq!(core_data_to_timestamp(f64::NAN), None);
+    assert_eq!(core_data_to_timestamp(f64::INFINITY), None);
+}
+
+#[test]
+fn combines_with_previous_json_export() {
+    let fixture = Fixture::new("combine").with_annotations();
+    let path = fixture.dir.join("all.json");
+    assert!(read_json_export(&path).unwrap().is_empty());
+
+    let annotations = fixture.read(&AnnotationQuery::default());
+    let json = format::Json(annotations.clone(), &format::Options::default())
+        .try_to_string()
+        .unwrap();
+    fs::write(&path, json).unwrap();
+
+    let mut combined = read_json_export(&path).unwrap();
+    assert_eq!(texts(&combined), texts(&annotations));
+    let mut fresh = annotations;
+    fresh[0].note = Some("Edited".into());
+    combined.append(&mut fresh);
+    let (combined, duplicates) = dedupe_by_uuid(combined);
+
+    assert_eq!(duplicates, 3);
+    assert_eq!(
+        texts(&combined),
+        ["First highlight", "Other book", "Second highlight"]
+    );
+    assert_eq!(combined[0].note.as_deref(), Some("Edited"));
+}
+
+#[test]
+fn numbers_annotations_in_book_order() {
+    let fixture = Fixture::new("numbered").with_annotations();
+    let mut annotations = fixture.read(&AnnotationQuery::default());
+    let options = format::Options::default();
+    let numbers =
+        |annotations: &[Annotation]| annotations.iter().map(|a| a.number).collect::<Vec<_>>();
+
+    number_annotations(&mut annotations, &options, NumberScope::Global);
+    assert_eq!(numbers(&annotations), [Some(1), Some(3), Some(2)]);
+    let preview = format::Preview(annotations.clone(), &options).to_string();
+    assert!(preview.contains("Book Two: [3] Other book"));
+
+    number_annotations(&mut annotations, &options, NumberScope::Book);
+    assert_eq!(numbers(&annotations), [Some(1), Some(1), Some(2)]);
+}
+
+#[test]
+fn measures_raw_length_with_sql_file() {
+    let fixture = Fixture::new("raw-length").with_annotations();
+    fixture.insert("A2", Some("  Ellipsis…"), None, 300.);
+    let sql = "SELECT a.ZANNOTATIONSELECTEDTEXT, a.ZANNOTATIONNOTE, a.ZFUTUREPROOFING6, l.ZTITLE
+        FROM ZAEANNOTATION a JOIN l.ZBKLIBRARYASSET l ON l.ZASSETID = a.ZANNOTATIONASSETID
+        ORDER BY a.ZFUTUREPROOFING6";
+    let query = AnnotationQuery::default();
+
+    let from_sql =
+        read_annotations_with_sql(fixture.annotation_db(), fixture.library_db(), sql, &query)
+            .unwrap();
+    let builtin = fixture.read(&query);
+    assert_eq!(from_sql.last().unwrap().raw_length, Some(11));
+    assert_eq!(builtin.last().unwrap().raw_length, Some(11));
+}
+
+#[test]
+fn filters_books_by_title() {
+    let filter = BookFilter::new(&["lord".into()], &[], &["towers".into()], &[]);
+    assert!(filter.matches("The Lord of the Rings", "A1"));
+    assert!(!filter.matches("The Two Towers (Lord of the Rings)", "A2"));
+    assert!(!filter.matches("Dune", "A3"));
+
+    let listed = BookFilter::new(&[], &["dune".into(), "a2".into()], &[], &[]);
+    assert!(listed.matches("DUNE", "A1"));
+    assert!(!listed.matches("Dune Messiah", "A1"));
+    assert!(listed.matches("Other", "A2"));
+
+    let glob = BookFilter::new(&[], &[], &[], &[Pattern::new("the lord*").unwrap()]);
+    assert!(glob.matches("The Lord of the Rings", "A1"));
+    assert!(!glob.matches("Lord Jim", "A1"));
+
+    assert!(BookFilter::new(&[], &[], &[], &[]).matches("Anything", "A1"));
+}
+
+#[test]
+fn merges_consecutive_highlights_in_location_order() {
+    assert!(cfi_sort_key("epubcfi(/6/4[ch1]!/4/10)") > cfi_sort_key("epubcfi(/6/4[ch,9]!/4/2)"));
+
+    let fixture = Fixture::new("merge").with_annotations();
+    let mut annotations = fixture.read(&AnnotationQuery::default());
+    annotations[0].location = Some("epubcfi(/6/4[ch1]!/4/10,/1:0,/1:5)".into());
+    annotations[2].location = Some("epubcfi(/6/4[ch1]!/4/2,/1:0,/1:5)".into());
+
+    let merged = merge_consecutive(annotations.clone(), Duration::seconds(100));
+    assert_eq!(merged.len(), 3);
+
+    let merged = merge_consecutive(annotations, Duration::seconds(300));
+    assert_eq!(
+        texts(&merged),
+        ["Other book", "Second highlight First highlight"]
+    );
+    assert_eq!(merged[1].word_count, 4);
+    assert_eq!(timestamp_to_core_data(merged[1].anotation_time), TS + 200.5);
+}
+
+#[test]
+fn upgrades_version_1_sync_file() {
+    let fixture = Fixture::new("sync-file");
+    let file = LastSyncFile(fixture.dir.join("last_sync"));
+    fs::write(&file.0, "2023-03-08T20:26:40+00:00\n").unwrap();
+    let time = Utc.with_ymd_and_hms(2023, 3, 8, 20, 26, 40).unwrap();
+
+    let state = file.read().unwrap().unwrap();
+    assert_eq!(state.time, time);
+    assert_eq!(state.last_uuid, None);
+
+    let state = SyncState {
+        time: time + Duration::milliseconds(500),
+        last_uuid: Some("U1".into()),
+    };
+    file.update(&state, SyncFormat::Epoch).unwrap();
+    assert_eq!(
+        fs::read_to_string(&file.0).unwrap(),
+        "1678307200.500000000\nversion=2\nlast_uuid=U1\n"
+    );
+    let state = file.read().unwrap().unwrap();
+    assert_eq!(state.time, time + Duration::milliseconds(500));
+    assert_eq!(state.last_uuid.as_deref(), Some("U1"));
+
+    fs::write(&file.0, "1678307200\nversion=3\n").unwrap();
+    assert!(file.read().is_err());
+}
+
+#[test]
+fn dedupes_annotations_by_note() {
+    let fixture = Fixture::new("dedupe-note").with_annotations();
+    fixture.insert("A2", Some("Other book"), Some("A longer note"), 300.);
+    fixture.insert("A2", Some("Other book"), Some("A note"), 400.);
+    fixture.insert("A1", Some("Other book"), None, 500.);
+    let annotations = fixture.read(&AnnotationQuery::default());
+
+    let deduped = dedupe_by_note(annotations);
+    assert_eq!(
+        texts(&deduped),
+        [
+            "First highlight",
+            "Second highlight",
+            "Other book",
+            "Other book"
+        ]
+    );
+    assert_eq!(deduped[2].note.as_deref(), Some("A longer note"));
+    assert_eq!(deduped[3].asset_id, "A1");
+}
+
+#[test]
+fn parses_epoch_with_fraction() {
+    let time = Utc.with_ymd_and_hms(2023, 3, 8, 20, 26, 40).unwrap();
+    assert_eq!(parse_epoch("1678307200"), Some(time));
+    assert_eq!(
+        parse_epoch("1678307200.000000001"),
+        Some(time + Duration::nanoseconds(1))
+    );
+    assert_eq!(
+        parse_epoch("1678307200.25"),
+        Some(time + Duration::milliseconds(250))
+    );
+    assert_eq!(parse_epoch("1678307200.0000000001"), None);
+    assert_eq!(parse_epoch("1678307200.-5"), None);
+    assert_eq!(parse_epoch("2023-03-08T20:26:40Z"), None);
+}
+
+#[test]
+fn formats_annotations_as_csv() {
+    let fixture = Fixture::new("csv").with_annotations();
+    let mut annotations = fixture.read(&AnnotationQuery::default());
+    annotations.truncate(2);
+    annotations[0].selected_text = "Say \"hi\",\nbye".into();
+
+    let csv = format::Csv(annotations, &format::Options::default()).to_string();
+    assert_eq!(
+        csv,
+        "book_title,author,time,color,selected_text,note\n\
+        Book One,Alice Author,2023-03-08T20:26:40+00:00,yellow,\"Say \"\"hi\"\",\nbye\",\n\
+        Book Two,,2023-03-08T20:28:20+00:00,yellow,Other book,A note"
+    );
+}
+
+#[test]
+fn splits_cfi_range() {
+    assert_eq!(
+        cfi_range("epubcfi(/6/4[ch,1]!/4/2,/1:0,/1:15)"),
+        Some((
+            "epubcfi(/6/4[ch,1]!/4/2/1:0)".to_string(),
+            "epubcfi(/6/4[ch,1]!/4/2/1:15)".to_string()
+        ))
+    );
+    assert_eq!(cfi_range("epubcfi(/6/4[ch1]!/4/2/1:0)"), None);
+    assert_eq!(cfi_range("/6/4!/4/2,/1:0,/1:15"), None);
+}
+
+#[test]
+fn formats_annotations_as_dot() {
+    let fixture = Fixture::new("dot").with_annotations();
+    let mut annotations = fixture.read(&AnnotationQuery::default());
+    annotations.truncate(2);
+    annotations[0].selected_text =
+        "A \"quoted\"\nhighlight which is way too long for a label".into();
+
+    let dot = format::Dot(annotations, &format::Options::default()).to_string();
+    assert_eq!(
+        dot,
+        r#"digraph highlights {
+    rankdir=LR;
+    b0 [label="Book One", shape=box];
+    h0 [label="A \"quoted\" highlight which is way too l…"];
+    b0 -> h0;
+    b1 [label="Book Two", shape=box];
+    h1 [label="Other book"];
+    b1 -> h1;
+    n1 [label="A note", shape=note];
+    h1 -> n1;
+}"#
+    );
+}
+
+#[test]
+fn formats_annotations_as_vcard() {
+    let fixture = Fixture::new("vcard").with_annotations();
+    let mut annotations = fixture.read(&AnnotationQuery::default());
+    annotations.truncate(1);
+    let long_text = "долгий текст ".repeat(10);
+    annotations[0].selected_text = format!("Ёлки, палки; {}", long_text);
+
+    let vcard = format::Vcard(annotations, &format::Options::default()).to_string();
+    assert!(
+        vcard.starts_with("BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Alice Author\r\nN:Author;Alice;;;\r\n")
+    );
+    assert!(vcard.ends_with("\r\nEND:VCARD\r\n"));
+    // Lines are folded by bytes without splitting multibyte characters
+    assert!(vcard.split("\r\n").all(|line| line.len() <= 75));
+    let unfolded = vcard.replace("\r\n ", "");
+    let note = format!("NOTE:Book One\\n\\nЁлки\\, палки\\; {}\r\n", long_text);
+    assert!(unfolded.contains(&note));
+}
+
+#[test]
+fn groups_annotations_by_note_tags() {
+    assert_eq!(
+        format::note_tags("My note #Idea. #to-read, #idea #books/sci-fi # 1#not"),
+        ["idea", "to-read", "books/sci-fi"]
+    );
+
+    let fixture = Fixture::new("tags").with_annotations();
+    let mut annotations = fixture.read(&AnnotationQuery::default());
+    annotations.truncate(2);
+    annotations[1].note = Some("#work #idea".into());
+
+    let tags = format::Tags(annotations, &format::Options::default()).to_string();
+    assert_eq!(
+        tags,
+        "# #idea\n\n> Other book\n\n#work #idea\n\n— Book Two\n\n\
+        # #work\n\n> Other book\n\n#work #idea\n\n— Book Two\n\n\
+        # untagged\n\n> First highlight\n\n— Book One"
+    );
+}
+
+#[test]
+fn formats_annotations_as_ics() {
+    let fixture = Fixture::new("ics").with_annotations();
+    let annotations = fixture.read(&AnnotationQuery::default())[1..2].to_vec();
+    let options = format::Options {
+        now: Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap(),
+        timezone: Timezone::Fixed(FixedOffset::east_opt(4 * 3600).unwrap()),
+        ..format::Options::default()
+    };
+
+    assert_eq!(
+        format::Ics(annotations, &options).to_string(),
+        "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//ibooks-export//EN\r\n\
+        BEGIN:VEVENT\r\nUID:h-u100@ibooks-export\r\nDTSTAMP:20240102T030405Z\r\n\
+        DTSTART;VALUE=DATE:20230309\r\nDTEND;VALUE=DATE:20230310\r\nSUMMARY:Book Two\r\n\
+        DESCRIPTION:Other book\\n\\nA note\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n"
+    );
+}
+
+#[test]
+fn makes_stable_annotation_slugs() {
+    assert_eq!(
+        annotation_slug(Some("3F2A-9B"), "Book", "Text"),
+        "h-3f2a-9b"
+    );
+    let slug = annotation_slug(None, "Book", "Text");
+    assert_eq!(slug, annotation_slug(Some(""), "Book", "Text"));
+    assert_ne!(slug, annotation_slug(None, "BookT", "ext"));
+    assert_eq!(slug.len(), "h-".len() + 16);
+}
+
+#[test]
+fn trims_trailing_ellipsis() {
+    assert_eq!(trim_ellipsis("  cut mid-sentence …\n"), "cut mid-sentence");
+    assert_eq!(trim_ellipsis("wait……"), "wait…");
+    assert_eq!(trim_ellipsis(" … "), "…");
+    assert_eq!(trim_ellipsis("no ellipsis"), "no ellipsis");
+}
+
+#[test]
+fn skips_unreadable_rows_with_skip_errors() {
+    let fixture = Fixture::new("skip-errors").with_annotations();
+    fixture.insert("A2", Some("Broken"), None, 300.);
+    Connection::open(fixture.annotation_db())
+        .unwrap()
+        .execute(
+            "UPDATE ZAEANNOTATION SET ZANNOTATIONSELECTEDTEXT = X'FF'
+            WHERE ZANNOTATIONSELECTEDTEXT = 'Broken'",
+            [],
+        )
+        .unwrap();
+    let read = |skip_errors| {
+        let query = AnnotationQuery {
+            skip_errors,
+            ..Default::default()
+        };
+        read_annotations(fixture.annotation_db(), &[], fixture.library_db(), &query)
+    };
+
+    assert!(read(false).is_err());
+    assert_eq!(
+        texts(&read(true).unwrap()),
+        ["First highlight", "Other book", "Second highlight"]
+    );
+}
+
+#[test]
+fn stores_schema_fingerprint_only_with_update() {
+    let fixture = Fixture::new("schema-fingerprint").with_annotations();
+    // Container layout for `--library-root`
+    for (db, dir) in [
+        (fixture.annotation_db(), "AEAnnotation"),
+        (fixture.library_db(), "BKLibrary"),
+    ] {
+        let dir = fixture.dir.join("Data/Documents").join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy(&db, dir.join(db.file_name().unwrap())).unwrap();
+    }
+    // The only test touching the state dir, so changing environment doesn't affect others
+    env::set_var("HOME", &fixture.dir);
+    env::remove_var("XDG_DATA_HOME");
+    let state_dir = state_dir().unwrap();
+    assert!(state_dir.starts_with(&fixture.dir));
+    let export_with = |flags: &[&str]| {
+        let mut args = vec![
+            "ibooks-export",
+            "--library-root",
+            fixture.dir.to_str().unwrap(),
+        ];
+        args.extend(flags);
+        export(&Args::try_parse_from(args).unwrap()).unwrap();
+    };
+
+    export_with(&["-a", "--json"]);
+    export_with(&["--validate"]);
+    assert!(!state_dir.exists());
+
+    export_with(&["--update"]);
+    assert!(state_dir.join("schema").exists());
+}
+
+#[test]
+fn groups_months_in_given_timezone() {
+    let fixture = Fixture::new("month-timezone");
+    // 2023-03-31 23:30 UTC, already April east of UTC
+    fixture.insert("A1", Some("Late highlight"), None, 1_998_200.);
+    let annotations = fixture.read(&AnnotationQuery::default());
+    let timezone = Timezone::Fixed(FixedOffset::east_opt(2 * 3600).unwrap());
+    let options = format::Options {
+        timezone,
+        ..format::Options::default()
+    };
+
+    let mermaid = format::Mermaid(annotations.clone(), &options).to_string();
+    assert!(mermaid.contains("2023-04 : Book One (1 highlights)"));
+    assert_eq!(
+        count_annotations(&annotations, CountBy::Month, &HashMap::new(), timezone),
+        [("2023-04".to_string(), 1)]
+    );
+}
+
+#[test]
+fn limits_request_headers() {
+    let read = |request: &str| serve::read_request_line(request.as_bytes()).map_err(|r| r.status);
+
+    assert_eq!(
+        read("GET /annotations HTTP/1.1\r\nHost: localhost\r\n\r\nignored"),
+        Ok("GET /annotations HTTP/1.1\r\n".to_string())
+    );
+    let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(200));
+    assert_eq!(
+        read(&many_headers),
+        Err("431 Request Header Fields Too Large")
+    );
+    let long_header = format!("GET / HTTP/1.1\r\nX-A: {}\r\n\r\n", "b".repeat(20_000));
+    assert_eq!(
+        read(&long_header),
+        Err("431 Request Header Fields Too Large")
+    );
+}
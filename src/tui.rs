@@ -0,0 +1,200 @@
+//! Interactive terminal UI for browsing annotations
+//!
+//! Books are listed on the left and highlights of the selected book on the right. Full text
+//! (and note) of the selected highlight is shown below the highlight list.
+//!
+//! Keys:
+//! - `↑`/`↓` – move selection in the focused list
+//! - `←`/`→`/`Tab` – switch focus between books and highlights
+//! - `s` – mark selected highlight as a sync point
+//! - `q`/`Esc` – quit
+use super::{local_time, Annotation, SyncState};
+use anyhow::Result;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::{Line, Text},
+    widgets::{Block, List, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use std::collections::BTreeMap;
+
+#[derive(PartialEq)]
+enum Focus {
+    Books,
+    Highlights,
+}
+
+struct App<'a> {
+    books: Vec<(&'a str, Vec<&'a Annotation>)>,
+    books_state: ListState,
+    highlights_state: ListState,
+    focus: Focus,
+    sync_point: Option<&'a Annotation>,
+}
+
+/// Runs interactive UI until user quits
+///
+/// Returns the sync state of the highlight marked as a sync point, if any.
+pub(crate) fn run(annotations: &[Annotation]) -> Result<Option<SyncState>> {
+    let mut terminal = ratatui::init();
+    let result = App::new(annotations).run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl<'a> App<'a> {
+    fn new(annotations: &'a [Annotation]) -> Self {
+        let mut books = BTreeMap::new();
+        for a in annotations {
+            books
+                .entry(a.book_title.as_str())
+                .or_insert_with(Vec::new)
+                .push(a);
+        }
+        let first = (!books.is_empty()).then_some(0);
+        Self {
+            books: books.into_iter().collect(),
+            books_state: ListState::default().with_selected(first),
+            highlights_state: ListState::default().with_selected(first),
+            focus: Focus::Books,
+            sync_point: None,
+        }
+    }
+
+    fn run(mut self, terminal: &mut DefaultTerminal) -> Result<Option<SyncState>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => {
+                    return Ok(self.sync_point.map(SyncState::from))
+                }
+                KeyCode::Left | KeyCode::Right | KeyCode::Tab => self.toggle_focus(),
+                KeyCode::Up => self.move_selection(-1),
+                KeyCode::Down => self.move_selection(1),
+                KeyCode::Char('s') => {
+                    if let Some(a) = self.selected_highlight() {
+                        self.sync_point = Some(a);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn highlights(&self) -> &[&'a Annotation] {
+        self.books_state
+            .selected()
+            .and_then(|idx| self.books.get(idx))
+            .map(|(_, highlights)| highlights.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn selected_highlight(&self) -> Option<&'a Annotation> {
+        let idx = self.highlights_state.selected()?;
+        self.highlights().get(idx).copied()
+    }
+
+    fn toggle_focus(&mut self) {
+        self.focus = match self.focus {
+            Focus::Books => Focus::Highlights,
+            Focus::Highlights => Focus::Books,
+        }
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        let highlights_len = self.highlights().len();
+        let (state, len) = match self.focus {
+            Focus::Books => (&mut self.books_state, self.books.len()),
+            Focus::Highlights => (&mut self.highlights_state, highlights_len),
+        };
+        if len == 0 {
+            return;
+        }
+        let idx = state.selected().unwrap_or(0) as isize + delta;
+        state.select(Some(idx.clamp(0, len as isize - 1) as usize));
+        if self.focus == Focus::Books {
+            self.highlights_state.select(Some(0));
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [books_area, right] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)])
+                .areas(main);
+        let [highlights_area, details_area] =
+            Layout::vertical([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(right);
+
+        let focused = |focus| {
+            if self.focus == focus {
+                Style::new().bold()
+            } else {
+                Style::new().dim()
+            }
+        };
+
+        let books = List::new(
+            self.books
+                .iter()
+                .map(|(title, highlights)| format!("{} ({})", title, highlights.len())),
+        )
+        .block(
+            Block::bordered()
+                .title("Books")
+                .style(focused(Focus::Books)),
+        )
+        .highlight_style(Style::new().reversed());
+
+        let highlights = List::new(self.highlights().iter().map(|a| {
+            let time = local_time(a.anotation_time);
+            let text = a.selected_text.lines().next().unwrap_or_default();
+            let marker = if self.sync_point.is_some_and(|s| std::ptr::eq(s, *a)) {
+                "* "
+            } else {
+                "  "
+            };
+            format!("{}{} {}", marker, time, text)
+        }))
+        .block(
+            Block::bordered()
+                .title("Highlights")
+                .style(focused(Focus::Highlights)),
+        )
+        .highlight_style(Style::new().reversed());
+
+        let mut details = Text::default();
+        if let Some(a) = self.selected_highlight() {
+            details.extend(Text::raw(a.selected_text.as_str()));
+            if let Some(note) = &a.note {
+                details.push_line(Line::default());
+                details.extend(Text::raw(note.as_str()).italic());
+            }
+        }
+        let details = Paragraph::new(details)
+            .wrap(Wrap { trim: false })
+            .block(Block::bordered());
+
+        let sync_point = self
+            .sync_point
+            .map(|a| local_time(a.anotation_time))
+            .unwrap_or_else(|| "not set".into());
+        let status_line = format!(
+            "↑↓ move  ←→ switch pane  s mark sync point  q quit | sync point: {}",
+            sync_point
+        );
+
+        frame.render_stateful_widget(books, books_area, &mut self.books_state);
+        frame.render_stateful_widget(highlights, highlights_area, &mut self.highlights_state);
+        frame.render_widget(details, details_area);
+        frame.render_widget(Line::raw(status_line).dim(), status);
+    }
+}